@@ -0,0 +1,318 @@
+// the tokenizers our indexes use, and working out which language text is in
+
+use serde_derive::{Deserialize, Serialize};
+use std::{iter::Peekable, mem, str::CharIndices};
+use tantivy::{
+    tokenizer::{Language, RemoveLongFilter, Stemmer, Token, TokenFilter, TokenStream, Tokenizer},
+    Index,
+};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+use whatlang::{Detector, Lang};
+
+/// languages we stem, with the code stored in the `lang` field
+pub const LANGUAGES: [(&str, Language); 3] = [
+    ("en", Language::English),
+    ("de", Language::German),
+    ("fr", Language::French),
+];
+
+/// how much of a document we look at to guess its language
+const DETECT_CHARS: usize = 4096;
+
+/// name of the tokenizer for content and filenames
+pub const TEXT_TOKENIZER: &str = "normalized";
+
+/// a step of the analyzer chain, applied to each word in order
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AnalyzerStep {
+    /// unicode compatibility normalization, so full-width letters, ligatures
+    /// and composed or decomposed accents all end up the same
+    Nfkc,
+    /// strip diacritics and replace letters like `ß` with ascii
+    AsciiFold,
+    Lowercase,
+}
+
+/// bump this whenever the tokenizers change what they produce, files
+/// indexed with an older version get re-indexed in the background
+pub const ANALYZER_VERSION: u32 = 1;
+
+/// identifies what the tokenizers do with the configured steps, so files
+/// are re-indexed if either changes
+pub fn version(steps: &[AnalyzerStep]) -> u32 {
+    // long step lists wrap around rather than spilling into the version
+    let steps = steps.iter().fold(0u32, |code, step| {
+        code.wrapping_mul(4).wrapping_add(*step as u32 + 1)
+    });
+
+    (ANALYZER_VERSION << 16) | (steps & 0xffff)
+}
+
+/// name of the tokenizer stemming text in a language
+pub fn stem_tokenizer(code: &str) -> String {
+    format!("stem_{}", code)
+}
+
+/// register our tokenizers, these aren't saved with the index so this has
+/// to happen every time one is opened
+pub fn register(index: &Index, steps: &[AnalyzerStep]) {
+    let normalize = NormalizeFilter {
+        steps: steps.to_vec(),
+    };
+
+    index.tokenizers().register(
+        TEXT_TOKENIZER,
+        WordTokenizer
+            .filter(RemoveLongFilter::limit(40))
+            .filter(normalize.clone()),
+    );
+
+    for (code, language) in LANGUAGES.iter() {
+        index.tokenizers().register(
+            &stem_tokenizer(code),
+            WordTokenizer
+                .filter(RemoveLongFilter::limit(40))
+                .filter(normalize.clone())
+                .filter(Stemmer::new(*language)),
+        );
+    }
+}
+
+/// guess which of `LANGUAGES` some text is in, None if it's too short or
+/// ambiguous to tell
+pub fn detect_language(text: &str) -> Option<&'static str> {
+    let sample = match text.char_indices().nth(DETECT_CHARS) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    };
+
+    let detector = Detector::with_whitelist(vec![Lang::Eng, Lang::Deu, Lang::Fra]);
+    let info = detector.detect(sample)?;
+
+    if !info.is_reliable() {
+        return None;
+    }
+
+    match info.lang() {
+        Lang::Eng => Some("en"),
+        Lang::Deu => Some("de"),
+        Lang::Fra => Some("fr"),
+        _ => None,
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || is_combining_mark(c)
+}
+
+/// chinese, japanese and korean characters, which are written without
+/// spaces between words
+fn is_cjk(c: char) -> bool {
+    match c as u32 {
+        0x1100..=0x11FF // hangul jamo
+        | 0x3040..=0x30FF // hiragana and katakana
+        | 0x31F0..=0x31FF // katakana phonetic extensions
+        | 0x3400..=0x4DBF // cjk extension a
+        | 0x4E00..=0x9FFF // cjk unified ideographs
+        | 0xAC00..=0xD7AF // hangul syllables
+        | 0xF900..=0xFAFF // cjk compatibility ideographs
+        | 0xFF66..=0xFF9F // halfwidth katakana
+        | 0x20000..=0x2FA1F => true, // cjk extension b onwards
+        _ => false,
+    }
+}
+
+/// splits on whitespace and punctuation like tantivy's `SimpleTokenizer`,
+/// but keeps combining marks in words so decomposed accents don't split them.
+/// runs of CJK characters become overlapping bigrams, since we can't tell
+/// where the words in them are.
+/// offsets point into the original text so snippets still work
+#[derive(Clone)]
+pub struct WordTokenizer;
+
+pub struct WordTokenStream<'a> {
+    text: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+    token: Token,
+    /// whether the last token was a bigram ending in the next character
+    in_cjk_run: bool,
+}
+
+impl<'a> Tokenizer<'a> for WordTokenizer {
+    type TokenStreamImpl = WordTokenStream<'a>;
+
+    fn token_stream(&self, text: &'a str) -> Self::TokenStreamImpl {
+        WordTokenStream {
+            text,
+            chars: text.char_indices().peekable(),
+            token: Token::default(),
+            in_cjk_run: false,
+        }
+    }
+}
+
+impl<'a> TokenStream for WordTokenStream<'a> {
+    fn advance(&mut self) -> bool {
+        self.token.text.clear();
+        self.token.position = self.token.position.wrapping_add(1);
+
+        while let Some((offset_from, c)) = self.chars.next() {
+            let offset_to = if is_cjk(c) {
+                match self.chars.peek() {
+                    Some(&(next_offset, next)) if is_cjk(next) => {
+                        self.in_cjk_run = true;
+                        next_offset + next.len_utf8()
+                    }
+                    // the end of a run, already covered by the last bigram
+                    _ if self.in_cjk_run => {
+                        self.in_cjk_run = false;
+                        continue;
+                    }
+                    // a character on its own
+                    _ => offset_from + c.len_utf8(),
+                }
+            } else if c.is_alphanumeric() {
+                let mut offset_to = offset_from + c.len_utf8();
+
+                while let Some(&(offset, c)) = self.chars.peek() {
+                    if !is_word_char(c) || is_cjk(c) {
+                        break;
+                    }
+
+                    offset_to = offset + c.len_utf8();
+                    self.chars.next();
+                }
+
+                offset_to
+            } else {
+                continue;
+            };
+
+            self.token.offset_from = offset_from;
+            self.token.offset_to = offset_to;
+            self.token.text.push_str(&self.text[offset_from..offset_to]);
+            return true;
+        }
+
+        false
+    }
+
+    fn token(&self) -> &Token {
+        &self.token
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        &mut self.token
+    }
+}
+
+/// ascii replacements for letters that don't decompose into a base letter
+/// and combining marks
+fn fold_special(c: char) -> Option<&'static str> {
+    Some(match c {
+        'ß' => "ss",
+        'æ' => "ae",
+        'Æ' => "AE",
+        'œ' => "oe",
+        'Œ' => "OE",
+        'ø' => "o",
+        'Ø' => "O",
+        'ł' => "l",
+        'Ł' => "L",
+        'đ' | 'ð' => "d",
+        'Đ' | 'Ð' => "D",
+        'þ' => "th",
+        'Þ' => "TH",
+        'ı' => "i",
+        _ => return None,
+    })
+}
+
+fn apply_step(step: AnalyzerStep, text: &str, output: &mut String) {
+    output.clear();
+
+    match step {
+        AnalyzerStep::Nfkc => output.extend(text.nfkc()),
+        AnalyzerStep::AsciiFold => {
+            for c in text.nfkd() {
+                if is_combining_mark(c) {
+                    continue;
+                }
+
+                match fold_special(c) {
+                    Some(s) => output.push_str(s),
+                    None => output.push(c),
+                }
+            }
+        }
+        AnalyzerStep::Lowercase => {
+            for c in text.chars() {
+                output.extend(c.to_lowercase());
+            }
+        }
+    }
+}
+
+/// runs each word through the configured analyzer steps
+#[derive(Clone)]
+pub struct NormalizeFilter {
+    steps: Vec<AnalyzerStep>,
+}
+
+impl<TailTokenStream> TokenFilter<TailTokenStream> for NormalizeFilter
+where
+    TailTokenStream: TokenStream,
+{
+    type ResultTokenStream = NormalizeTokenStream<TailTokenStream>;
+
+    fn transform(&self, token_stream: TailTokenStream) -> Self::ResultTokenStream {
+        NormalizeTokenStream {
+            steps: self.steps.clone(),
+            buffer: String::with_capacity(100),
+            tail: token_stream,
+        }
+    }
+}
+
+pub struct NormalizeTokenStream<TailTokenStream> {
+    steps: Vec<AnalyzerStep>,
+    buffer: String,
+    tail: TailTokenStream,
+}
+
+impl<TailTokenStream> TokenStream for NormalizeTokenStream<TailTokenStream>
+where
+    TailTokenStream: TokenStream,
+{
+    fn token(&self) -> &Token {
+        self.tail.token()
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        self.tail.token_mut()
+    }
+
+    fn advance(&mut self) -> bool {
+        if !self.tail.advance() {
+            return false;
+        }
+
+        for &step in &self.steps {
+            let text = &mut self.tail.token_mut().text;
+
+            // every step leaves ascii alone apart from lowercasing
+            if text.is_ascii() {
+                if step == AnalyzerStep::Lowercase {
+                    text.make_ascii_lowercase();
+                }
+                continue;
+            }
+
+            apply_step(step, text, &mut self.buffer);
+            mem::swap(text, &mut self.buffer);
+        }
+
+        true
+    }
+}
@@ -0,0 +1,100 @@
+// deciding when the index writer should commit
+
+use std::time::{Duration, Instant};
+
+use super::config;
+
+pub struct CommitPolicy {
+    max_docs: u64,
+    max_pending_bytes: u64,
+    max_delay: Duration,
+    idle: Duration,
+}
+
+impl CommitPolicy {
+    pub fn new(config: &config::CommitConfig) -> CommitPolicy {
+        CommitPolicy {
+            max_docs: config.max_docs,
+            max_pending_bytes: config.max_pending_mb * 1_000_000,
+            max_delay: Duration::from_secs(config.max_delay_secs),
+            idle: Duration::from_secs(config.idle_secs),
+        }
+    }
+}
+
+/// what's been written since the last commit
+pub struct PendingWrites {
+    policy: CommitPolicy,
+    docs: u64,
+    bytes: u64,
+    first_write: Option<Instant>,
+    last_write: Option<Instant>,
+    forced: bool,
+}
+
+impl PendingWrites {
+    pub fn new(policy: CommitPolicy) -> PendingWrites {
+        PendingWrites {
+            policy,
+            docs: 0,
+            bytes: 0,
+            first_write: None,
+            last_write: None,
+            forced: false,
+        }
+    }
+
+    pub fn record(&mut self, docs: u64, bytes: u64) {
+        let now = Instant::now();
+
+        self.docs += docs;
+        self.bytes += bytes;
+        self.first_write.get_or_insert(now);
+        self.last_write = Some(now);
+    }
+
+    /// commit on the next check, whatever the policy says
+    pub fn force(&mut self) {
+        self.forced = true;
+    }
+
+    /// how long until a commit is due if nothing else gets written,
+    /// None if there's nothing pending
+    pub fn time_until_due(&self) -> Option<Duration> {
+        let (first, last) = match (self.first_write, self.last_write) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return None,
+        };
+
+        let due = std::cmp::min(first + self.policy.max_delay, last + self.policy.idle);
+        let now = Instant::now();
+
+        Some(if due > now {
+            due - now
+        } else {
+            Duration::from_secs(0)
+        })
+    }
+
+    pub fn should_commit(&self) -> bool {
+        if self.forced {
+            return true;
+        }
+
+        if self.first_write.is_none() {
+            return false;
+        }
+
+        self.docs >= self.policy.max_docs
+            || self.bytes >= self.policy.max_pending_bytes
+            || self.time_until_due() == Some(Duration::from_secs(0))
+    }
+
+    pub fn reset(&mut self) {
+        self.docs = 0;
+        self.bytes = 0;
+        self.first_write = None;
+        self.last_write = None;
+        self.forced = false;
+    }
+}
@@ -7,6 +7,10 @@ use snafu::{OptionExt, ResultExt, Snafu};
 use std::{fs, io::Write, path::PathBuf};
 use toml;
 
+use super::analyzer::AnalyzerStep;
+use super::history::HistoryRoot;
+use super::secrets::{EncryptedPolicy, EncryptedRoot, SecretProvider};
+
 #[derive(Debug, Snafu)]
 pub enum Error {
     #[snafu(display("Could not locate config directory"))]
@@ -23,16 +27,331 @@ pub enum Error {
     },
     #[snafu(display("Could not do something with config: {}", source))]
     GeneralConfigError { source: config::ConfigError },
+    #[snafu(display("Invalid indexing config: {}", reason))]
+    InvalidIndexing { reason: String },
+    #[snafu(display("Invalid merge config: {}", reason))]
+    InvalidMerge { reason: String },
+    #[snafu(display("Invalid collections: {}", reason))]
+    InvalidCollections { reason: String },
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// tantivy wants at least this much heap per writer thread
+const MIN_HEAP_PER_THREAD_MB: usize = 3;
+/// and no more than this
+const MAX_HEAP_PER_THREAD_MB: usize = 4000;
+/// more writer threads than this just make more segments to merge
+const MAX_DEFAULT_WRITER_THREADS: usize = 8;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResourceProfile {
+    /// use every cpu and plenty of memory
+    Default,
+    /// one thread and a small heap, for laptops and small machines
+    LowResource,
+}
+
+/// how much of the machine indexing may use, anything left unset comes
+/// from the profile
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IndexingConfig {
+    pub profile: ResourceProfile,
+    /// threads extracting text from files
+    pub workers: Option<usize>,
+    /// threads the index writer uses
+    pub writer_threads: Option<usize>,
+    /// memory the index writer buffers documents in, in megabytes.
+    /// this is shared between its threads
+    pub writer_heap_mb: Option<usize>,
+    /// how many files can wait for a worker before the crawl blocks
+    pub queue_depth: Option<usize>,
+}
+
+/// when writes get committed and become searchable, whichever of these
+/// happens first
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommitConfig {
+    /// after this many documents have been written
+    pub max_docs: u64,
+    /// once this many megabytes of text are waiting
+    pub max_pending_mb: u64,
+    /// at most this many seconds after the first write
+    pub max_delay_secs: u64,
+    /// once nothing has been written for this many seconds
+    pub idle_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MergePolicyKind {
+    /// merge segments of about the same size once there are enough of them
+    Log,
+    /// never merge in the background, only when optimizing
+    None,
+}
+
+/// how the index writer merges segments in the background. every commit
+/// makes a new segment, and searches slow down as they pile up
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MergeConfig {
+    pub policy: MergePolicyKind,
+    /// how many segments of about the same size get merged at once
+    pub min_merge_segments: usize,
+    /// segments with fewer documents than this count as the same size
+    pub min_layer_docs: u32,
+    /// how far apart, in log2 of their documents, segments of the same
+    /// size can be
+    pub level_log_size: f64,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct IndexingResources {
+    pub workers: usize,
+    pub writer_threads: usize,
+    pub writer_heap_bytes: usize,
+    pub queue_depth: usize,
+}
+
+impl IndexingResources {
+    /// a share of these for each of `n` indexes running at once. every
+    /// index still gets a thread of each kind and enough heap for them
+    fn split(&self, n: usize) -> IndexingResources {
+        let heap_mb = (self.writer_heap_bytes / 1_000_000 / n).max(MIN_HEAP_PER_THREAD_MB);
+        let writer_threads = (self.writer_threads / n)
+            .min(heap_mb / MIN_HEAP_PER_THREAD_MB)
+            .max(1);
+        let heap_mb = heap_mb.min(writer_threads * MAX_HEAP_PER_THREAD_MB);
+
+        IndexingResources {
+            workers: (self.workers / n).max(1),
+            writer_threads,
+            writer_heap_bytes: heap_mb * 1_000_000,
+            queue_depth: (self.queue_depth / n).max(1),
+        }
+    }
+}
+
+impl IndexingConfig {
+    pub fn resources(&self) -> IndexingResources {
+        let cpus = num_cpus::get();
+
+        let (workers, writer_threads, writer_heap_mb, queue_depth) = match self.profile {
+            ResourceProfile::Default => (cpus, cpus, 500, cpus),
+            ResourceProfile::LowResource => (1, 1, 32, 2),
+        };
+        let writer_heap_mb = self.writer_heap_mb.unwrap_or(writer_heap_mb);
+
+        // every writer thread needs its share of the heap
+        let writer_threads = writer_threads
+            .min(MAX_DEFAULT_WRITER_THREADS)
+            .min(writer_heap_mb / MIN_HEAP_PER_THREAD_MB)
+            .max(1);
+
+        IndexingResources {
+            workers: self.workers.unwrap_or(workers),
+            writer_threads: self.writer_threads.unwrap_or(writer_threads),
+            writer_heap_bytes: writer_heap_mb * 1_000_000,
+            queue_depth: self.queue_depth.unwrap_or(queue_depth),
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        let resources = self.resources();
+
+        let reason = if resources.workers == 0 {
+            "workers must be at least 1".to_owned()
+        } else if resources.writer_threads == 0 {
+            "writer_threads must be at least 1".to_owned()
+        } else if resources.queue_depth == 0 {
+            "queue_depth must be at least 1".to_owned()
+        } else {
+            let per_thread = resources.writer_heap_bytes / 1_000_000 / resources.writer_threads;
+
+            if per_thread < MIN_HEAP_PER_THREAD_MB {
+                format!(
+                    "writer_heap_mb must be at least {}MB per writer thread",
+                    MIN_HEAP_PER_THREAD_MB
+                )
+            } else if per_thread > MAX_HEAP_PER_THREAD_MB {
+                format!(
+                    "writer_heap_mb can be at most {}MB per writer thread",
+                    MAX_HEAP_PER_THREAD_MB
+                )
+            } else {
+                return Ok(());
+            }
+        };
+
+        Err(Error::InvalidIndexing { reason })
+    }
+}
+
+impl MergeConfig {
+    fn validate(&self) -> Result<()> {
+        let reason = if self.min_merge_segments < 2 {
+            // a single segment would be merged into itself forever
+            "min_merge_segments must be at least 2"
+        } else if self.level_log_size <= 0.0 {
+            "level_log_size must be more than 0"
+        } else {
+            return Ok(());
+        };
+
+        Err(Error::InvalidMerge {
+            reason: reason.to_owned(),
+        })
+    }
+}
+
+/// a set of roots indexed and searched apart from the others. anything left
+/// unset comes from the top level of the config
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CollectionConfig {
+    pub name: String,
+    pub root_globs: Vec<String>,
+    /// defaults to a directory named after the collection in `index_location`
+    pub index_location: Option<PathBuf>,
+    pub indexed_exts: Option<Vec<String>>,
+    pub ignored_globs: Option<Vec<String>>,
+    /// man pages are only walked for collections that list them, so they
+    /// aren't indexed once per collection
+    pub man_paths: Option<Vec<String>>,
+}
+
+/// results under `from` are shown under `to`, for indexes built where a
+/// drive is mounted somewhere else
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PathPrefix {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+/// an index built by someone else, like one published on a network drive.
+/// it's searched along with the collections but never written to
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SharedIndex {
+    pub name: String,
+    pub index_location: PathBuf,
+    #[serde(default)]
+    pub path_prefixes: Vec<PathPrefix>,
+}
+
+/// the name of the only collection when none are configured
+pub const DEFAULT_COLLECTION: &str = "default";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
     pub index_location: PathBuf,
     pub root_globs: Vec<String>,
+    /// `json` files are read as slack and discord chat exports, so it's
+    /// left out by default. add it if you keep exports in the roots
     pub indexed_exts: Vec<String>,
     pub ignored_globs: Vec<String>,
+    /// file name globs of shell histories, these are indexed even if ignored
+    pub history_files: Vec<String>,
+    /// globs of directories holding man and info pages, walked along with the roots
+    pub man_paths: Vec<String>,
+    /// command used to open media at a subtitle cue,
+    /// `{file}` and `{offset}` (in seconds) are substituted
+    pub media_player: Vec<String>,
+    /// how words in content and filenames are normalized, in order.
+    /// any of `nfkc`, `ascii_fold` and `lowercase`
+    pub analyzer: Vec<AnalyzerStep>,
+    /// what to keep from encrypted files in roots not listed in `encrypted_roots`
+    pub encrypted_policy: EncryptedPolicy,
+    pub encrypted_roots: Vec<EncryptedRoot>,
+    /// roots whose files keep their past versions searchable with `version:any`
+    pub history_roots: Vec<HistoryRoot>,
+    /// where to get passwords for encrypted pdfs and archives. pdfs are
+    /// decrypted with `qpdf`, which needs to be installed
+    pub secret_provider: SecretProvider,
+    pub indexing: IndexingConfig,
+    pub commit: CommitConfig,
+    pub merge: MergeConfig,
+    /// when empty everything is one collection, using the settings above
+    pub collections: Vec<CollectionConfig>,
+    /// read only indexes searched alongside the collections, they need to
+    /// have been built with the same schema version and analyzer
+    pub shared_indexes: Vec<SharedIndex>,
+}
+
+impl Config {
+    /// the name and config of each collection, these are indexed separately
+    pub fn collections(&self) -> Vec<(String, Config)> {
+        if self.collections.is_empty() {
+            return vec![(DEFAULT_COLLECTION.to_owned(), self.clone())];
+        }
+
+        // the collections are indexed at the same time, so they share the
+        // threads and memory between them
+        let share = self.indexing.resources().split(self.collections.len());
+        let indexing = IndexingConfig {
+            profile: self.indexing.profile,
+            workers: Some(share.workers),
+            writer_threads: Some(share.writer_threads),
+            writer_heap_mb: Some(share.writer_heap_bytes / 1_000_000),
+            queue_depth: Some(share.queue_depth),
+        };
+
+        self.collections
+            .iter()
+            .map(|c| {
+                let mut config = self.clone();
+
+                config.index_location = c
+                    .index_location
+                    .clone()
+                    .unwrap_or_else(|| self.index_location.join(&c.name));
+                config.root_globs = c.root_globs.clone();
+                config.indexed_exts = c
+                    .indexed_exts
+                    .clone()
+                    .unwrap_or_else(|| self.indexed_exts.clone());
+                config.ignored_globs = c
+                    .ignored_globs
+                    .clone()
+                    .unwrap_or_else(|| self.ignored_globs.clone());
+                config.man_paths = c.man_paths.clone().unwrap_or_default();
+                config.indexing = indexing.clone();
+                config.collections = Vec::new();
+
+                (c.name.clone(), config)
+            })
+            .collect()
+    }
+
+    fn validate_collections(&self) -> Result<()> {
+        // shared indexes are picked in the same selector, so their names
+        // can't clash either
+        let configs = self.collections();
+        let collections: Vec<(&str, &PathBuf)> = configs
+            .iter()
+            .map(|(name, c)| (name.as_str(), &c.index_location))
+            .chain(
+                self.shared_indexes
+                    .iter()
+                    .map(|s| (s.name.as_str(), &s.index_location)),
+            )
+            .collect();
+
+        for (i, (name, location)) in collections.iter().enumerate() {
+            let reason = if name.is_empty() {
+                "every collection needs a name".to_owned()
+            } else if collections[..i].iter().any(|(n, _)| n == name) {
+                format!("there's more than one collection named {}", name)
+            } else if collections[..i].iter().any(|(_, l)| l == location) {
+                format!("{} shares its index location with another collection", name)
+            } else {
+                continue;
+            };
+
+            return Err(Error::InvalidCollections { reason });
+        }
+
+        Ok(())
+    }
 }
 
 pub fn load_config() -> Result<Config> {
@@ -50,7 +369,12 @@ pub fn load_config() -> Result<Config> {
     let user_dirs = UserDirs::new().expect("Where's your home dir?");
 
     config
-        .set_default("indexed_exts",  vec!["txt", "org", "pdf", "md", "rst"])
+        .set_default(
+            "indexed_exts",
+            vec![
+                "txt", "org", "pdf", "md", "rst", "srt", "vtt", "ics", "vcf", "zip",
+            ],
+        )
         .context(GeneralConfigError)?;
     config
         .set_default("root_globs", vec![user_dirs.home_dir().to_str()])
@@ -58,6 +382,69 @@ pub fn load_config() -> Result<Config> {
     config
         .set_default("ignored_globs", vec![".*"])
         .context(GeneralConfigError)?;
+    config
+        .set_default(
+            "history_files",
+            vec![".bash_history", ".zsh_history", ".histfile"],
+        )
+        .context(GeneralConfigError)?;
+    config
+        .set_default(
+            "man_paths",
+            vec!["/usr/share/man", "/usr/local/share/man", "/usr/share/info"],
+        )
+        .context(GeneralConfigError)?;
+    config
+        .set_default("media_player", vec!["mpv", "--start={offset}", "{file}"])
+        .context(GeneralConfigError)?;
+    config
+        .set_default("analyzer", vec!["nfkc", "ascii_fold", "lowercase"])
+        .context(GeneralConfigError)?;
+    config
+        .set_default("encrypted_policy", "index_only")
+        .context(GeneralConfigError)?;
+    config
+        .set_default("encrypted_roots", Vec::<String>::new())
+        .context(GeneralConfigError)?;
+    config
+        .set_default("history_roots", Vec::<String>::new())
+        .context(GeneralConfigError)?;
+    config
+        .set_default("secret_provider.type", "none")
+        .context(GeneralConfigError)?;
+    config
+        .set_default("indexing.profile", "default")
+        .context(GeneralConfigError)?;
+    config
+        .set_default("commit.max_docs", 1000)
+        .context(GeneralConfigError)?;
+    config
+        .set_default("commit.max_pending_mb", 100)
+        .context(GeneralConfigError)?;
+    config
+        .set_default("commit.max_delay_secs", 30)
+        .context(GeneralConfigError)?;
+    config
+        .set_default("commit.idle_secs", 2)
+        .context(GeneralConfigError)?;
+    config
+        .set_default("merge.policy", "log")
+        .context(GeneralConfigError)?;
+    config
+        .set_default("merge.min_merge_segments", 8)
+        .context(GeneralConfigError)?;
+    config
+        .set_default("merge.min_layer_docs", 10_000)
+        .context(GeneralConfigError)?;
+    config
+        .set_default("merge.level_log_size", 0.75)
+        .context(GeneralConfigError)?;
+    config
+        .set_default("collections", Vec::<String>::new())
+        .context(GeneralConfigError)?;
+    config
+        .set_default("shared_indexes", Vec::<String>::new())
+        .context(GeneralConfigError)?;
 
     let config_dir = project_dirs.config_dir().with_extension("toml");
 
@@ -77,5 +464,10 @@ pub fn load_config() -> Result<Config> {
             filename: project_dirs.config_dir(),
         })?;
 
-    config.try_into().context(GeneralConfigError)
+    let config: Config = config.try_into().context(GeneralConfigError)?;
+    config.indexing.validate()?;
+    config.merge.validate()?;
+    config.validate_collections()?;
+
+    Ok(config)
 }
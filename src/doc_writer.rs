@@ -0,0 +1,466 @@
+// writing documents to the index. files with the same content share one set
+// of documents, which lists the paths of all of them
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tantivy::{
+    collector::{Count, TopDocs},
+    query::{Query, RangeQuery, TermQuery},
+    schema::{Document, IndexRecordOption},
+    DocAddress, IndexReader, IndexWriter, Searcher, SegmentId, SegmentReader,
+};
+
+use super::file_record::{self, ContentHash};
+use super::history::{History, HistoryRoot};
+use super::indexer::{DocSchema, SegmentStats};
+use super::last_modified_cache::LastModifiedCache;
+
+/// what to do with the documents of a file
+pub enum WriteAction {
+    /// take the file out of the index, its content's documents only go if
+    /// no other file shares them
+    Remove,
+    /// index documents freshly extracted from the file
+    Write {
+        hash: Option<ContentHash>,
+        docs: Vec<Document>,
+    },
+    /// the file has the same content as one that's already indexed, so it
+    /// only needs listing alongside it
+    Share { hash: ContentHash },
+}
+
+/// the documents of some content, without anything about where it is
+struct Group {
+    docs: Vec<Document>,
+    paths: Vec<PathBuf>,
+}
+
+pub struct DocWriter {
+    writer: IndexWriter,
+    /// only reloaded when we commit, so it always shows the committed index
+    reader: IndexReader,
+    schema: DocSchema,
+    modified_cache: LastModifiedCache,
+    history: History,
+    /// groups changed since the last commit, which the reader can't see.
+    /// a group that's been removed has no paths
+    groups: HashMap<ContentHash, Group>,
+    /// the content of files which changed since the last commit, which the
+    /// modified cache doesn't know about yet
+    hashes: HashMap<PathBuf, Option<ContentHash>>,
+    /// the past versions of files which changed since the last commit, as
+    /// (when they were superseded, id)
+    versions: HashMap<PathBuf, Vec<(u64, String)>>,
+    /// documents written and roughly how big they were, for the commit policy
+    written: (u64, u64),
+}
+
+impl DocWriter {
+    pub fn new(
+        writer: IndexWriter,
+        reader: IndexReader,
+        schema: DocSchema,
+        modified_cache: LastModifiedCache,
+        history: History,
+    ) -> DocWriter {
+        DocWriter {
+            writer,
+            reader,
+            schema,
+            modified_cache,
+            history,
+            groups: HashMap::new(),
+            hashes: HashMap::new(),
+            versions: HashMap::new(),
+            written: (0, 0),
+        }
+    }
+
+    pub fn modified_cache(&self) -> &LastModifiedCache {
+        &self.modified_cache
+    }
+
+    /// returns how many documents were written and roughly how big they
+    /// were, None if the file couldn't be written
+    pub fn apply(&mut self, path: &Path, action: WriteAction) -> Option<(u64, u64)> {
+        self.written = (0, 0);
+
+        // keep what the file was before its content changes or it's deleted
+        if let Some(root) = self.history.root_for(path).cloned() {
+            let changes = match &action {
+                WriteAction::Remove => true,
+                WriteAction::Write { hash, .. } => self.group_of(path) != *hash,
+                WriteAction::Share { hash } => self.group_of(path) != Some(*hash),
+            };
+
+            if changes {
+                self.archive(path, &root);
+            }
+        }
+
+        // documents only this file had, like ones of encrypted content
+        self.writer.delete_term(self.schema.doc_id_term(path));
+        self.written.0 += 1;
+
+        match action {
+            WriteAction::Remove => self.detach(path),
+            WriteAction::Write { hash, docs } => match hash {
+                Some(hash) if docs.iter().all(|d| self.schema.is_stored(d)) => {
+                    if self.group_of(path) != Some(hash) {
+                        self.detach(path);
+                    }
+                    self.attach(path, hash, Some(docs));
+                }
+                // documents we don't keep all of can't be rewritten when
+                // another copy turns up, so they aren't shared
+                _ => {
+                    self.detach(path);
+                    let id = path.to_str().unwrap();
+                    for doc in self.schema.place(docs, id, &[path.to_path_buf()]) {
+                        self.add(doc);
+                    }
+                }
+            },
+            WriteAction::Share { hash } => {
+                if self.group_of(path) != Some(hash) {
+                    self.detach(path);
+                }
+                if !self.attach(path, hash, None) {
+                    return None;
+                }
+            }
+        }
+
+        Some(self.written)
+    }
+
+    pub fn commit(&mut self) -> tantivy::Result<()> {
+        let committed = self.writer.commit();
+
+        self.groups.clear();
+        self.hashes.clear();
+        self.versions.clear();
+
+        committed?;
+
+        // the commit still went through, we just might miss some copies
+        if let Err(e) = self.reader.reload() {
+            eprintln!("Couldn't reload the index after committing: {}", e);
+        }
+
+        Ok(())
+    }
+
+    /// merge every segment into one, dropping deleted documents. this
+    /// carries on in the background, searches see it once it's done
+    pub fn optimize(&mut self) -> tantivy::Result<()> {
+        let searcher = self.reader.searcher();
+        let stats = SegmentStats::of(&searcher);
+
+        if stats.segments == 0 || (stats.segments == 1 && stats.deleted_docs == 0) {
+            return Ok(());
+        }
+
+        println!(
+            "Optimizing the index: merging {} segments, {:.0}% of documents are deleted",
+            stats.segments,
+            stats.deleted_ratio() * 100.0
+        );
+
+        let segments: Vec<SegmentId> = searcher
+            .segment_readers()
+            .iter()
+            .map(SegmentReader::segment_id)
+            .collect();
+
+        // the merge tells us when it's done, but nothing's waiting to hear
+        let _ = self.writer.merge(&segments)?;
+
+        Ok(())
+    }
+
+    /// remove past versions older than their roots keep, which may not have
+    /// changed in a while. returns what was written like `apply`
+    pub fn prune_expired(&mut self) -> (u64, u64) {
+        self.written = (0, 0);
+
+        let now = file_record::now_secs();
+        let searcher = self.reader.searcher();
+        let mut expired = HashSet::new();
+
+        for root in self.history.roots() {
+            let oldest = match root.oldest_kept(now) {
+                Some(oldest) => oldest,
+                None => continue,
+            };
+
+            let query = RangeQuery::new_u64(self.schema.superseded_at(), 0..oldest);
+
+            for doc in all_docs(&searcher, &query) {
+                let past_of = doc.get_first(self.schema.past_of()).and_then(|v| v.text());
+
+                // versions of files in a more specific root go by its limits
+                if past_of.and_then(|p| self.history.root_for(p)) != Some(root) {
+                    continue;
+                }
+
+                if let Some(id) = doc.get_first(self.schema.doc_id()).and_then(|v| v.text()) {
+                    expired.insert(id.to_owned());
+                }
+            }
+        }
+
+        if !expired.is_empty() {
+            println!("Removing {} expired versions", expired.len());
+        }
+
+        for id in expired {
+            self.writer.delete_term(self.schema.doc_id_term(&id));
+            self.written.0 += 1;
+        }
+
+        self.written
+    }
+
+    /// keep the current content of a file as a past version
+    fn archive(&mut self, path: &Path, root: &HistoryRoot) {
+        let hash = match self.group_of(path) {
+            Some(hash) => hash,
+            None => return,
+        };
+
+        // only grouped content can be rebuilt from what's stored
+        let docs = match self.load_group(&hash) {
+            Some(group) => {
+                if !group.paths.iter().any(|p| p == path) {
+                    return;
+                }
+                group.docs.clone()
+            }
+            None => return,
+        };
+
+        let modified = self
+            .modified_cache
+            .get(path)
+            .map(|r| r.mtime_ns / 1_000_000_000);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_else(|_| Duration::from_secs(0));
+        let superseded_at = now.as_secs();
+        let id = format!("past:{}:{}", now.as_nanos(), path.to_str().unwrap());
+
+        for doc in self
+            .schema
+            .place_version(docs, &id, path, modified, superseded_at)
+        {
+            self.add(doc);
+        }
+
+        let mut versions = self.take_versions(path);
+        versions.push((superseded_at, id));
+        self.prune(path, root, versions);
+    }
+
+    /// the past versions of a file, as (when they were superseded, id)
+    fn take_versions(&mut self, path: &Path) -> Vec<(u64, String)> {
+        if let Some(versions) = self.versions.remove(path) {
+            return versions;
+        }
+
+        let query = TermQuery::new(self.schema.past_of_term(path), IndexRecordOption::Basic);
+        let mut versions: Vec<(u64, String)> = all_docs(&self.reader.searcher(), &query)
+            .into_iter()
+            .filter_map(|doc| {
+                let superseded_at = doc.get_first(self.schema.superseded_at())?.u64_value();
+                let id = doc.get_first(self.schema.doc_id())?.text()?.to_owned();
+                Some((superseded_at, id))
+            })
+            .collect();
+
+        // each record of a version is a document
+        versions.sort();
+        versions.dedup();
+        versions
+    }
+
+    /// remove the versions of a file its root doesn't keep
+    fn prune(&mut self, path: &Path, root: &HistoryRoot, mut versions: Vec<(u64, String)>) {
+        let now = file_record::now_secs();
+        let mut kept = Vec::new();
+
+        // newest first
+        versions.sort_by(|a, b| b.0.cmp(&a.0));
+
+        for (superseded_at, id) in versions {
+            if root.keeps(kept.len(), superseded_at, now) {
+                kept.push((superseded_at, id));
+            } else {
+                self.writer.delete_term(self.schema.doc_id_term(&id));
+                self.written.0 += 1;
+            }
+        }
+
+        self.versions.insert(path.to_path_buf(), kept);
+    }
+
+    /// the content a file was indexed with
+    fn group_of(&self, path: &Path) -> Option<ContentHash> {
+        match self.hashes.get(path) {
+            Some(hash) => *hash,
+            None => self.modified_cache.get(path).and_then(|r| r.hash),
+        }
+    }
+
+    fn load_group(&mut self, hash: &ContentHash) -> Option<&mut Group> {
+        if !self.groups.contains_key(hash) {
+            let group = self.read_group(hash)?;
+            self.groups.insert(*hash, group);
+        }
+
+        self.groups.get_mut(hash)
+    }
+
+    /// a group as it is in the committed index
+    fn read_group(&self, hash: &ContentHash) -> Option<Group> {
+        let id = file_record::content_id(hash);
+        let query = TermQuery::new(self.schema.doc_id_term(&id), IndexRecordOption::Basic);
+        let searcher = self.reader.searcher();
+
+        let count = searcher.search(&query, &Count).ok()?;
+        if count == 0 {
+            return None;
+        }
+
+        let hits: Vec<(f32, DocAddress)> =
+            searcher.search(&query, &TopDocs::with_limit(count)).ok()?;
+        let docs = hits
+            .into_iter()
+            .map(|(_, addr)| searcher.doc(addr).ok())
+            .collect::<Option<Vec<_>>>()?;
+
+        let paths = docs
+            .first()?
+            .get_all(self.schema.full_path())
+            .into_iter()
+            .filter_map(|v| v.text())
+            .map(PathBuf::from)
+            .collect();
+
+        Some(Group {
+            docs: docs
+                .iter()
+                .map(|d| self.schema.without_location(d))
+                .collect(),
+            paths,
+        })
+    }
+
+    /// take a file out of the group it's in
+    fn detach(&mut self, path: &Path) {
+        let hash = match self.group_of(path) {
+            Some(hash) => hash,
+            None => return,
+        };
+
+        self.hashes.insert(path.to_path_buf(), None);
+
+        let changed = match self.load_group(&hash) {
+            Some(group) => {
+                let listed = group.paths.len();
+                group.paths.retain(|p| p != path);
+                group.paths.len() != listed
+            }
+            None => false,
+        };
+
+        if changed {
+            self.rewrite(&hash);
+        }
+    }
+
+    /// list a file in the group of its content, replacing the group's
+    /// documents if we have new ones. false if there are no documents for
+    /// the content
+    fn attach(&mut self, path: &Path, hash: ContentHash, docs: Option<Vec<Document>>) -> bool {
+        let exists = self
+            .load_group(&hash)
+            .map_or(false, |g| !g.paths.is_empty());
+
+        if !exists && docs.is_none() {
+            return false;
+        }
+
+        let group = self.groups.entry(hash).or_insert_with(|| Group {
+            docs: Vec::new(),
+            paths: Vec::new(),
+        });
+        let listed = group.paths.iter().any(|p| p == path);
+
+        match docs {
+            Some(docs) => group.docs = docs,
+            None if listed => return true,
+            None => (),
+        }
+
+        if !listed {
+            group.paths.push(path.to_path_buf());
+        }
+
+        self.hashes.insert(path.to_path_buf(), Some(hash));
+        self.rewrite(&hash);
+
+        true
+    }
+
+    /// replace the documents of a group with ones listing its current paths
+    fn rewrite(&mut self, hash: &ContentHash) {
+        let id = file_record::content_id(hash);
+
+        self.writer.delete_term(self.schema.doc_id_term(&id));
+        self.written.0 += 1;
+
+        let docs = match self.groups.get(hash) {
+            Some(group) if !group.paths.is_empty() => {
+                self.schema.place(group.docs.clone(), &id, &group.paths)
+            }
+            _ => return,
+        };
+
+        for doc in docs {
+            self.add(doc);
+        }
+    }
+
+    fn add(&mut self, doc: Document) {
+        self.written.0 += 1;
+        self.written.1 += doc
+            .field_values()
+            .iter()
+            .map(|v| v.value().text().map(|t| t.len() as u64).unwrap_or(8))
+            .sum::<u64>();
+
+        self.writer.add_document(doc);
+    }
+}
+
+/// every document matching a query
+fn all_docs(searcher: &Searcher, query: &dyn Query) -> Vec<Document> {
+    let count = match searcher.search(query, &Count) {
+        Ok(count) if count > 0 => count,
+        _ => return Vec::new(),
+    };
+
+    let hits: Vec<(f32, DocAddress)> = match searcher.search(query, &TopDocs::with_limit(count)) {
+        Ok(hits) => hits,
+        Err(_) => return Vec::new(),
+    };
+
+    hits.into_iter()
+        .filter_map(|(_, addr)| searcher.doc(addr).ok())
+        .collect()
+}
@@ -1,4 +1,4 @@
-use crossbeam_channel::{unbounded, Receiver};
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError};
 use glob;
 use notify::{watcher, RecursiveMode, Watcher};
 use snafu::{ResultExt, Snafu};
@@ -6,6 +6,7 @@ use std::{collections::HashSet, path::{Path, PathBuf}, time::Duration};
 use walkdir::WalkDir;
 
 use super::config;
+use super::file_record::FileStat;
 use super::last_modified_cache;
 
 #[derive(Debug, Snafu)]
@@ -25,17 +26,106 @@ pub enum Error {
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// how long to wait for a change to the roots before re-indexing another
+/// stale file, changes always go first
+const STALE_PAUSE: Duration = Duration::from_millis(100);
+
 #[derive(Debug, Copy, Clone)]
 pub enum CollectorOp {
     Index,
     ReIndex,
     Delete,
+    /// a file we've already indexed has been moved here, see `moved_from`
+    Move,
+}
+
+/// the sections man pages are sorted into, each has a `man<section>` directory
+const MAN_SECTIONS: &[&str] = &[
+    "0", "0p", "1", "1p", "2", "3", "3p", "4", "5", "6", "7", "8", "9", "l", "n",
+];
+
+/// which extractor a file should be sent through
+#[derive(Debug, Copy, Clone)]
+pub enum FileKind {
+    Text,
+    Subtitles,
+    Calendar,
+    ShellHistory,
+    ChatExport,
+    ManPage,
+    InfoPage,
+    Pdf,
+    Archive,
+}
+
+impl FileKind {
+    /// name and version of the extractor for this kind of file, bump the
+    /// version when an extractor changes what it pulls out of files
+    pub fn extractor(self) -> (&'static str, u32) {
+        match self {
+            FileKind::Text => ("text", 1),
+            FileKind::Subtitles => ("subtitles", 1),
+            FileKind::Calendar => ("calendar", 1),
+            FileKind::ShellHistory => ("shell_history", 1),
+            FileKind::ChatExport => ("chat_export", 1),
+            FileKind::ManPage => ("man_page", 1),
+            FileKind::InfoPage => ("info_page", 1),
+            FileKind::Pdf => ("pdf", 1),
+            FileKind::Archive => ("archive", 1),
+        }
+    }
+
+    fn from_ext(ext: &str) -> Option<FileKind> {
+        Some(match ext {
+            "txt" | "org" | "md" | "rst" => FileKind::Text,
+            "srt" | "vtt" => FileKind::Subtitles,
+            "ics" | "vcf" => FileKind::Calendar,
+            "json" => FileKind::ChatExport,
+            "pdf" => FileKind::Pdf,
+            "zip" => FileKind::Archive,
+            _ => return None,
+        })
+    }
+
+    /// man pages live in `man<section>` directories and are named
+    /// `<name>.<section>`, optionally gzipped. info pages are named
+    /// `<name>.info`, `<name>.info-<n>` or either gzipped
+    fn from_manual_path(path: &Path) -> Option<FileKind> {
+        let name = path.file_name()?.to_str()?;
+        let name = name.trim_end_matches(".gz");
+
+        if name.ends_with(".info") || name.contains(".info-") {
+            return Some(FileKind::InfoPage);
+        }
+
+        let section = Path::new(name).extension()?.to_str()?;
+        let dir = path.parent()?.file_name()?.to_str()?;
+
+        // `man3` holds pages like `printf.3` and `SSL_new.3ssl`
+        let mut split = dir.splitn(2, "man");
+        let dir_section = match (split.next(), split.next()) {
+            (Some(""), Some(s)) if MAN_SECTIONS.contains(&s) => s,
+            _ => return None,
+        };
+
+        if section.starts_with(dir_section) {
+            Some(FileKind::ManPage)
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct FileEntry {
     full_path: PathBuf,
     operation: CollectorOp,
+    /// None for deleted files
+    kind: Option<FileKind>,
+    /// None for deleted files
+    stat: Option<FileStat>,
+    /// where the file was before, for moves
+    moved_from: Option<PathBuf>,
 }
 
 impl FileEntry {
@@ -51,29 +141,84 @@ impl FileEntry {
             .expect("Couldn't convert OsStr to str")
     }
 
-    pub fn file_ext(&self) -> &str {
-        self.full_path
-            .extension()
-            .expect("Couldn't get file ext")
-            .to_str()
-            .expect("Couldn't convert OsStr to str")
-    }
-
     pub fn operation(&self) -> CollectorOp {
         self.operation
     }
+
+    pub fn kind(&self) -> Option<FileKind> {
+        self.kind
+    }
+
+    pub fn stat(&self) -> Option<&FileStat> {
+        self.stat.as_ref()
+    }
+
+    pub fn moved_from(&self) -> Option<&Path> {
+        self.moved_from.as_ref().map(PathBuf::as_path)
+    }
+
+    /// name of the user owning the file
+    pub fn owner(&self) -> Option<String> {
+        owner_of(&self.full_path)
+    }
+}
+
+/// name of the user owning a file
+pub fn owner_of(path: &Path) -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+
+    let uid = std::fs::metadata(path).ok()?.uid();
+    let user = users::get_user_by_uid(uid)?;
+
+    user.name().to_str().map(str::to_owned)
+}
+
+pub enum CollectorEvent {
+    File(FileEntry),
+    /// the initial walk of every root is done, everything after this
+    /// comes from watching them. incomplete if some roots couldn't be opened
+    CrawlFinished { complete: bool },
+}
+
+/// what the walk couldn't get into
+#[derive(Default)]
+struct Unwalked {
+    /// roots that couldn't be opened at all
+    roots: Vec<PathBuf>,
+    /// directories and files inside the roots that couldn't be read
+    paths: Vec<PathBuf>,
+}
+
+impl Unwalked {
+    /// whether walking `roots` would have come across the path
+    fn covers(&self, roots: &[PathBuf], path: &Path) -> bool {
+        roots.iter().any(|r| path.starts_with(r))
+            && !self
+                .roots
+                .iter()
+                .chain(&self.paths)
+                .any(|p| path.starts_with(p))
+    }
 }
 
 enum FileCollectorIteratorMode {
-    WalkDir(Option<walkdir::IntoIter>, Vec<PathBuf>),
+    /// (walk of the current root, roots left, what couldn't be walked)
+    WalkDir(Option<walkdir::IntoIter>, Vec<PathBuf>, Unwalked),
     Notify(Receiver<notify::Result<notify::Event>>),
 }
 
-enum CIterMAction {
+enum Walked {
     /// (list of paths, were paths deleted)
-    Result(Option<(Vec<PathBuf>, bool)>),
+    Paths(Vec<PathBuf>, bool),
+    CrawlFinished(Unwalked),
+    /// nothing changed for a while
+    Idle,
+}
+
+enum CIterMAction {
+    Result(Option<Walked>),
     Stop,
-    IntoNotify,
+    IntoNotify(Unwalked),
 }
 
 impl FileCollectorIteratorMode {
@@ -99,15 +244,20 @@ impl FileCollectorIteratorMode {
             .map(|r| WalkDir::new(r).follow_links(true).into_iter())
     }
 
-    fn next_inner(&mut self) -> CIterMAction {
+    /// `idle_after` is how long to wait for a change once watching before
+    /// giving up with `Walked::Idle`, None to wait forever
+    fn next_inner(&mut self, idle_after: Option<Duration>) -> CIterMAction {
         use notify::event::EventKind;
 
         match self {
-            FileCollectorIteratorMode::WalkDir(it, roots) => loop {
+            FileCollectorIteratorMode::WalkDir(it, roots, unwalked) => loop {
                 if it.is_none() {
                     let new_it = match FileCollectorIteratorMode::fetch_next_root_iter(roots) {
                         Some(it) => it,
-                        None => return CIterMAction::IntoNotify,
+                        None => {
+                            let unwalked = std::mem::replace(unwalked, Unwalked::default());
+                            return CIterMAction::IntoNotify(unwalked);
+                        }
                     };
 
                     it.replace(new_it);
@@ -116,17 +266,43 @@ impl FileCollectorIteratorMode {
                 let dent = match it.as_mut().unwrap().next() {
                     Some(result) => match result {
                         Ok(v) => v,
-                        Err(_e) => continue,
+                        // the root itself, it's unmounted or we can't read it
+                        Err(ref e) if e.depth() == 0 => {
+                            eprintln!("Couldn't walk {:?}: {}", e.path(), e);
+                            unwalked.roots.extend(e.path().map(Path::to_path_buf));
+                            *it = None;
+                            continue;
+                        }
+                        Err(e) => {
+                            unwalked.paths.extend(e.path().map(Path::to_path_buf));
+                            continue;
+                        }
                     },
-                    None => return CIterMAction::IntoNotify,
+                    // on to the next root
+                    None => {
+                        *it = None;
+                        continue;
+                    }
                 };
 
-                return CIterMAction::Result(Some((vec![dent.path().to_path_buf()], false)));
+                return CIterMAction::Result(Some(Walked::Paths(
+                    vec![dent.path().to_path_buf()],
+                    false,
+                )));
             },
             FileCollectorIteratorMode::Notify(ch) => loop {
-                let e = match ch.recv().ok() {
-                    Some(e) => e,
-                    None => return CIterMAction::Stop,
+                let e = match idle_after {
+                    Some(timeout) => match ch.recv_timeout(timeout) {
+                        Ok(e) => e,
+                        Err(RecvTimeoutError::Timeout) => {
+                            return CIterMAction::Result(Some(Walked::Idle))
+                        }
+                        Err(RecvTimeoutError::Disconnected) => return CIterMAction::Stop,
+                    },
+                    None => match ch.recv().ok() {
+                        Some(e) => e,
+                        None => return CIterMAction::Stop,
+                    },
                 };
 
                 if let Ok(e) = e {
@@ -135,19 +311,20 @@ impl FileCollectorIteratorMode {
                         EventKind::Remove(_) => true,
                         _ => continue,
                     };
-                    return CIterMAction::Result(Some((e.paths, flag)));
+                    return CIterMAction::Result(Some(Walked::Paths(e.paths, flag)));
                 }
             },
         }
     }
 
-    fn next(&mut self, roots: &[PathBuf]) -> Option<(Vec<PathBuf>, bool)> {
-        loop {
-            let r = self.next_inner();
-            match r {
-                CIterMAction::Result(r) => return r,
-                CIterMAction::Stop => return None,
-                CIterMAction::IntoNotify => self.into_notify(roots),
+    fn next(&mut self, roots: &[PathBuf], idle_after: Option<Duration>) -> Option<Walked> {
+        let r = self.next_inner(idle_after);
+        match r {
+            CIterMAction::Result(r) => r,
+            CIterMAction::Stop => None,
+            CIterMAction::IntoNotify(unwalked) => {
+                self.into_notify(roots);
+                Some(Walked::CrawlFinished(unwalked))
             }
         }
     }
@@ -157,9 +334,18 @@ pub struct FilesCollectorIteror {
     ignored: Vec<glob::Pattern>,
     roots: Vec<PathBuf>,
     exts: HashSet<String>,
+    history_files: Vec<glob::Pattern>,
+    /// the roots from `man_paths`, only files in these can be manual pages
+    man_roots: Vec<PathBuf>,
     last_modified_cache: last_modified_cache::LastModifiedCache,
     current_iterator: FileCollectorIteratorMode,
     extra_paths: Vec<(PathBuf, bool)>,
+    /// files indexed by an older extractor or analyzer, re-indexed one at a
+    /// time whenever the roots are quiet
+    stale: Vec<PathBuf>,
+    /// everything the crawl came across, files we have records of that
+    /// aren't in here were deleted while we weren't running
+    seen: HashSet<PathBuf>,
 }
 
 impl FilesCollectorIteror {
@@ -167,30 +353,162 @@ impl FilesCollectorIteror {
         ignored: Vec<glob::Pattern>,
         roots: Vec<PathBuf>,
         exts: HashSet<String>,
+        history_files: Vec<glob::Pattern>,
+        man_roots: Vec<PathBuf>,
         last_modified_cache: last_modified_cache::LastModifiedCache,
     ) -> Self {
         let walker_roots = roots.clone();
 
+        // files that were sent to the indexer but never committed before we
+        // last stopped. ones that still exist are marked stale so the walk
+        // picks them up again, ones that don't are deleted from the index
+        let mut extra_paths = Vec::new();
+        match last_modified_cache.take_uncommitted() {
+            Ok(uncommitted) => {
+                for path in uncommitted {
+                    if path.exists() {
+                        let _ = last_modified_cache.mark_stale(&path);
+                    } else {
+                        extra_paths.push((path, true));
+                    }
+                }
+            }
+            Err(e) => eprintln!("Couldn't read uncommitted files: {}", e),
+        }
+
         FilesCollectorIteror {
             ignored,
             roots,
             exts,
+            history_files,
+            man_roots,
             last_modified_cache,
-            current_iterator: FileCollectorIteratorMode::WalkDir(None, walker_roots),
-            extra_paths: Vec::new(),
+            current_iterator: FileCollectorIteratorMode::WalkDir(
+                None,
+                walker_roots,
+                Unwalked::default(),
+            ),
+            extra_paths,
+            stale: Vec::new(),
+            seen: HashSet::new(),
         }
     }
 
+    fn is_history_file(&self, entry: &Path) -> bool {
+        entry
+            .file_name()
+            .map(|s| self.history_files.iter().any(|p| p.matches(s.to_str().unwrap())))
+            .unwrap_or(false)
+    }
+
     fn predicate(&self, entry: &std::path::Path) -> bool {
+        // history files are usually dotfiles, don't let the ignore globs hide them
+        if self.is_history_file(entry) {
+            return true;
+        }
+
         entry
             .file_name()
             .map(|s| !self.ignored.iter().any(|p| p.matches(s.to_str().unwrap())))
             .unwrap_or(false)
     }
+
+    fn kind_of(&self, entry: &Path) -> Option<FileKind> {
+        if self.is_history_file(entry) {
+            return Some(FileKind::ShellHistory);
+        }
+
+        // a `man1/foo.1` elsewhere is more likely something else entirely
+        if self.man_roots.iter().any(|r| entry.starts_with(r)) {
+            if let Some(kind) = FileKind::from_manual_path(entry) {
+                return Some(kind);
+            }
+        }
+
+        entry
+            .extension()
+            .and_then(std::ffi::OsStr::to_str)
+            .filter(|e| self.exts.contains(*e))
+            .and_then(FileKind::from_ext)
+    }
+
+    /// queue deletes for the files we have records of which the crawl
+    /// didn't come across. only files under roots we fully walked count,
+    /// ones in unmounted or unreadable places are most likely still there
+    fn reconcile_deleted(&mut self, unwalked: &Unwalked) {
+        let seen = std::mem::replace(&mut self.seen, HashSet::new());
+
+        let paths = match self.last_modified_cache.paths() {
+            Ok(paths) => paths,
+            Err(e) => {
+                eprintln!("Couldn't look for deleted files: {}", e);
+                return;
+            }
+        };
+
+        let missing: Vec<_> = paths
+            .into_iter()
+            .filter(|p| !seen.contains(p) && unwalked.covers(&self.roots, p))
+            .map(|p| (p, true))
+            .collect();
+
+        if !missing.is_empty() {
+            println!("{} files were deleted since we last ran", missing.len());
+        }
+
+        self.extra_paths.extend(missing);
+    }
+
+    fn find_stale(&mut self) {
+        let found = self
+            .last_modified_cache
+            .stale_files(|path| self.kind_of(path).map(FileKind::extractor));
+
+        match found {
+            Ok(stale) => {
+                if !stale.is_empty() {
+                    println!("{} files were indexed by older extractors", stale.len());
+                }
+                self.stale = stale;
+            }
+            Err(e) => eprintln!("Couldn't look for stale files: {}", e),
+        }
+    }
+
+    /// the next stale file that still needs re-indexing
+    fn next_stale(&mut self) -> Option<FileEntry> {
+        while let Some(path) = self.stale.pop() {
+            let kind = match self.kind_of(&path) {
+                Some(k) => k,
+                None => continue,
+            };
+
+            let stat = match FileStat::of(&path) {
+                Ok(stat) => stat,
+                Err(_) => continue,
+            };
+
+            // it may have been re-indexed since we looked
+            match self.last_modified_cache.check_stale(&path, kind.extractor()) {
+                Ok(true) => (),
+                _ => continue,
+            }
+
+            return Some(FileEntry {
+                full_path: path,
+                operation: CollectorOp::ReIndex,
+                kind: Some(kind),
+                stat: Some(stat),
+                moved_from: None,
+            });
+        }
+
+        None
+    }
 }
 
 impl Iterator for FilesCollectorIteror {
-    type Item = Result<FileEntry>;
+    type Item = Result<CollectorEvent>;
 
     fn next(&mut self) -> Option<Self::Item> {
         use super::last_modified_cache::FileCacheAction;
@@ -199,7 +517,27 @@ impl Iterator for FilesCollectorIteror {
             let (path, was_removed) = match self.extra_paths.pop() {
                 Some(p) => p,
                 None => {
-                    let (mut paths, was_removed) = self.current_iterator.next(&self.roots)?;
+                    let idle_after = if self.stale.is_empty() {
+                        None
+                    } else {
+                        Some(STALE_PAUSE)
+                    };
+
+                    let (mut paths, was_removed) =
+                        match self.current_iterator.next(&self.roots, idle_after)? {
+                            Walked::Paths(paths, was_removed) => (paths, was_removed),
+                            Walked::CrawlFinished(unwalked) => {
+                                self.reconcile_deleted(&unwalked);
+                                self.find_stale();
+                                return Some(Ok(CollectorEvent::CrawlFinished {
+                                    complete: unwalked.roots.is_empty(),
+                                }));
+                            }
+                            Walked::Idle => match self.next_stale() {
+                                Some(entry) => return Some(Ok(CollectorEvent::File(entry))),
+                                None => continue,
+                            },
+                        };
 
                     let path_to_use = match paths.pop() {
                         Some(p) => p,
@@ -218,15 +556,22 @@ impl Iterator for FilesCollectorIteror {
 
             if was_removed && self.last_modified_cache.remove_file(&path) {
                 println!("saw deleted file: {:?}", path);
-                return Some(Ok(FileEntry {
+                return Some(Ok(CollectorEvent::File(FileEntry {
                     full_path: path,
                     operation: CollectorOp::Delete,
-                }));
+                    kind: None,
+                    stat: None,
+                    moved_from: None,
+                })));
+            }
+
+            if let FileCollectorIteratorMode::WalkDir(..) = self.current_iterator {
+                self.seen.insert(path.clone());
             }
 
             if !self.predicate(&path) {
                 if path.is_dir() {
-                    if let FileCollectorIteratorMode::WalkDir(Some(ref mut it), _) =
+                    if let FileCollectorIteratorMode::WalkDir(Some(ref mut it), ..) =
                         self.current_iterator
                     {
                         it.skip_current_dir();
@@ -240,39 +585,47 @@ impl Iterator for FilesCollectorIteror {
                 continue;
             }
 
-            // skip extensions we don't care about
-            if !path
-                .extension()
-                .and_then(std::ffi::OsStr::to_str)
-                .map(|e| self.exts.contains(e))
-                .unwrap_or(false)
-            {
-                continue;
-            }
+            // skip files we don't know how to index
+            let kind = match self.kind_of(&path) {
+                Some(k) => k,
+                None => continue,
+            };
 
-            let f = std::fs::File::open(&path).unwrap();
+            let stat = match FileStat::of(&path) {
+                Ok(stat) => stat,
+                Err(_) => continue,
+            };
 
-            let modified: u64 = f
-                .metadata()
-                .ok()?
-                .modified()
-                .ok()?
-                .duration_since(std::time::UNIX_EPOCH)
-                .ok()?
-                .as_secs();
+            let action = self.last_modified_cache.check_file(&path, &stat).ok()?;
 
-            let action = self.last_modified_cache.check_file(&path, modified).ok()?;
+            let mut moved_from = None;
 
             let op = match action {
                 FileCacheAction::Outdated => CollectorOp::ReIndex,
                 FileCacheAction::UptoDate => continue,
-                FileCacheAction::NotIndexed => CollectorOp::Index,
+                FileCacheAction::NotIndexed => {
+                    match self.last_modified_cache.find_moved(&path, &stat) {
+                        // the old path is journaled along with the new one
+                        Some(from) => {
+                            println!("saw moved file: {:?} -> {:?}", from, path);
+                            self.last_modified_cache.remove_file(&from);
+                            // its delete has been sent already
+                            self.seen.insert(from.clone());
+                            moved_from = Some(from);
+                            CollectorOp::Move
+                        }
+                        None => CollectorOp::Index,
+                    }
+                }
             };
 
-            return Some(Ok(FileEntry {
+            return Some(Ok(CollectorEvent::File(FileEntry {
                 full_path: path,
                 operation: op,
-            }));
+                kind: Some(kind),
+                stat: Some(stat),
+                moved_from,
+            })));
         }
     }
 }
@@ -280,17 +633,9 @@ pub fn collect_files(
     config: &config::Config,
     last_modified_cache: last_modified_cache::LastModifiedCache,
 ) -> Result<FilesCollectorIteror> {
-    let roots: Vec<glob::Paths> = config
-        .root_globs
-        .iter()
-        .map(|g| glob::glob(g).with_context(|| GlobParseError { glob: g.to_owned() }))
-        .collect::<Result<Vec<_>>>()?;
-
-    let roots: Vec<PathBuf> = roots
-        .into_iter()
-        .flatten()
-        .map(|p| p.context(GlobError))
-        .collect::<Result<Vec<_>>>()?;
+    let man_roots = expand_globs(&config.man_paths)?;
+    let mut roots = expand_globs(&config.root_globs)?;
+    roots.extend(man_roots.iter().cloned());
 
     let ignored: Vec<glob::Pattern> = config
         .ignored_globs
@@ -298,10 +643,32 @@ pub fn collect_files(
         .map(|g| glob::Pattern::new(g).with_context(|| GlobParseError { glob: g.to_owned() }))
         .collect::<Result<Vec<_>>>()?;
 
+    let history_files: Vec<glob::Pattern> = config
+        .history_files
+        .iter()
+        .map(|g| glob::Pattern::new(g).with_context(|| GlobParseError { glob: g.to_owned() }))
+        .collect::<Result<Vec<_>>>()?;
+
     Ok(FilesCollectorIteror::new(
         ignored,
         roots,
         config.indexed_exts.iter().cloned().collect(),
+        history_files,
+        man_roots,
         last_modified_cache,
     ))
 }
+
+/// the paths matching any of the globs
+fn expand_globs(globs: &[String]) -> Result<Vec<PathBuf>> {
+    let paths: Vec<glob::Paths> = globs
+        .iter()
+        .map(|g| glob::glob(g).with_context(|| GlobParseError { glob: g.to_owned() }))
+        .collect::<Result<Vec<_>>>()?;
+
+    paths
+        .into_iter()
+        .flatten()
+        .map(|p| p.context(GlobError))
+        .collect()
+}
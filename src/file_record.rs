@@ -0,0 +1,315 @@
+// what the modified cache remembers about each indexed file, and how it's
+// laid out on disk
+
+use sha2::{Digest, Sha256};
+use std::{
+    convert::TryInto,
+    fs,
+    io::{self, Read},
+    os::unix::fs::MetadataExt,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// bumped whenever the layout of an encoded record changes
+const RECORD_VERSION: u8 = 1;
+
+const HAS_SIZE: u8 = 1;
+const HAS_INODE: u8 = 1 << 1;
+const HAS_HASH: u8 = 1 << 2;
+const HAS_EXTRACTOR: u8 = 1 << 3;
+const HAS_ANALYZER: u8 = 1 << 4;
+
+pub type ContentHash = [u8; 32];
+
+/// what a stat of a file tells us
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FileStat {
+    /// nanoseconds since the epoch
+    pub mtime_ns: u64,
+    pub size: u64,
+    pub dev: u64,
+    pub inode: u64,
+}
+
+impl FileStat {
+    pub fn of<P: AsRef<Path>>(path: P) -> io::Result<FileStat> {
+        let meta = fs::metadata(path)?;
+
+        // anything from before 1970 counts as 1970
+        let mtime_ns = if meta.mtime() < 0 {
+            0
+        } else {
+            (meta.mtime() as u64)
+                .saturating_mul(1_000_000_000)
+                .saturating_add(meta.mtime_nsec() as u64)
+        };
+
+        Ok(FileStat {
+            mtime_ns,
+            size: meta.size(),
+            dev: meta.dev(),
+            inode: meta.ino(),
+        })
+    }
+}
+
+/// hash the contents of a file
+pub fn hash_file<P: AsRef<Path>>(path: P) -> io::Result<ContentHash> {
+    let mut f = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 64 * 1024];
+
+    loop {
+        let n = f.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.input(&buf[..n]);
+    }
+
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&hasher.result());
+    Ok(hash)
+}
+
+/// what the documents of some content are identified by in the index
+pub fn content_id(hash: &ContentHash) -> String {
+    let hex: String = hash.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha256:{}", hex)
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// everything but the mtime is optional since records migrated from the
+/// old cache only had whole second mtimes
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileRecord {
+    /// nanoseconds since the epoch
+    pub mtime_ns: u64,
+    pub size: Option<u64>,
+    /// (device, inode)
+    pub inode: Option<(u64, u64)>,
+    pub hash: Option<ContentHash>,
+    /// name and version of the extractor that indexed the file
+    pub extractor: Option<(String, u32)>,
+    /// version of the analyzer the file's text was tokenized with
+    pub analyzer: Option<u32>,
+    /// seconds since the epoch
+    pub indexed_at: u64,
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+}
+
+impl FileRecord {
+    pub fn new(stat: &FileStat) -> FileRecord {
+        FileRecord {
+            mtime_ns: stat.mtime_ns,
+            size: Some(stat.size),
+            inode: Some((stat.dev, stat.inode)),
+            hash: None,
+            extractor: None,
+            analyzer: None,
+            indexed_at: now_secs(),
+        }
+    }
+
+    /// whether this record came from the old cache, which only knew the
+    /// mtime to the second
+    pub fn is_legacy(&self) -> bool {
+        self.size.is_none()
+    }
+
+    /// little endian, a version byte, a byte of flags for which optional
+    /// fields are present, then the fields
+    pub fn encode(&self) -> Vec<u8> {
+        let mut flags = 0;
+        let mut out = vec![RECORD_VERSION, 0];
+
+        out.extend_from_slice(&self.mtime_ns.to_le_bytes());
+        out.extend_from_slice(&self.indexed_at.to_le_bytes());
+
+        if let Some(size) = self.size {
+            flags |= HAS_SIZE;
+            out.extend_from_slice(&size.to_le_bytes());
+        }
+
+        if let Some((dev, inode)) = self.inode {
+            flags |= HAS_INODE;
+            out.extend_from_slice(&dev.to_le_bytes());
+            out.extend_from_slice(&inode.to_le_bytes());
+        }
+
+        if let Some(hash) = &self.hash {
+            flags |= HAS_HASH;
+            out.extend_from_slice(hash);
+        }
+
+        if let Some(analyzer) = self.analyzer {
+            flags |= HAS_ANALYZER;
+            out.extend_from_slice(&analyzer.to_le_bytes());
+        }
+
+        // the extractor id takes the rest of the record, so it goes last
+        if let Some((id, version)) = &self.extractor {
+            flags |= HAS_EXTRACTOR;
+            out.extend_from_slice(&version.to_le_bytes());
+            out.extend_from_slice(id.as_bytes());
+        }
+
+        out[1] = flags;
+        out
+    }
+
+    /// decode a record, including the bare native endian seconds the cache
+    /// used to store
+    pub fn decode(raw: &[u8]) -> Option<FileRecord> {
+        if raw.len() == 8 {
+            let secs = u64::from_ne_bytes(raw.try_into().ok()?);
+
+            return Some(FileRecord {
+                mtime_ns: secs.saturating_mul(1_000_000_000),
+                size: None,
+                inode: None,
+                hash: None,
+                extractor: None,
+                analyzer: None,
+                indexed_at: 0,
+            });
+        }
+
+        let mut r = Reader(raw);
+
+        let header = r.take(2)?;
+        if header[0] != RECORD_VERSION {
+            return None;
+        }
+        let flags = header[1];
+
+        let mtime_ns = r.u64()?;
+        let indexed_at = r.u64()?;
+
+        let size = if flags & HAS_SIZE != 0 {
+            Some(r.u64()?)
+        } else {
+            None
+        };
+
+        let inode = if flags & HAS_INODE != 0 {
+            Some((r.u64()?, r.u64()?))
+        } else {
+            None
+        };
+
+        let hash = if flags & HAS_HASH != 0 {
+            Some(r.take(32)?.try_into().ok()?)
+        } else {
+            None
+        };
+
+        let analyzer = if flags & HAS_ANALYZER != 0 {
+            Some(r.u32()?)
+        } else {
+            None
+        };
+
+        let extractor = if flags & HAS_EXTRACTOR != 0 {
+            let version = r.u32()?;
+            let id = String::from_utf8(r.0.to_vec()).ok()?;
+            Some((id, version))
+        } else {
+            None
+        };
+
+        Some(FileRecord {
+            mtime_ns,
+            size,
+            inode,
+            hash,
+            extractor,
+            analyzer,
+            indexed_at,
+        })
+    }
+
+    /// whether a file has the content this record was made from even though
+    /// its mtime changed. the size is checked first so most edited files
+    /// never get hashed
+    pub fn same_content<P: AsRef<Path>>(&self, path: P, stat: &FileStat) -> bool {
+        if self.size != Some(stat.size) {
+            return false;
+        }
+
+        match &self.hash {
+            Some(hash) => hash_file(path).ok().as_ref() == Some(hash),
+            None => false,
+        }
+    }
+
+    /// whether the file was indexed by an older extractor or analyzer than
+    /// the ones given, records that don't say count as stale
+    pub fn is_stale(&self, extractor: (&str, u32), analyzer: u32) -> bool {
+        let extractor_matches = match &self.extractor {
+            Some((id, version)) => (id.as_str(), *version) == extractor,
+            None => false,
+        };
+
+        !extractor_matches || self.analyzer != Some(analyzer)
+    }
+
+    /// whether a file with this stat is unchanged since it was recorded
+    pub fn matches(&self, stat: &FileStat) -> bool {
+        if self.is_legacy() {
+            return self.mtime_ns / 1_000_000_000 == stat.mtime_ns / 1_000_000_000;
+        }
+
+        self.mtime_ns == stat.mtime_ns && self.size == Some(stat.size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_records_are_migrated() {
+        let record = FileRecord::decode(&1_560_000_000u64.to_ne_bytes()).unwrap();
+
+        assert!(record.is_legacy());
+        assert_eq!(record.mtime_ns, 1_560_000_000_000_000_000);
+    }
+
+    #[test]
+    fn far_future_legacy_records_saturate() {
+        let secs = u64::max_value() / 1000;
+        let record = FileRecord::decode(&secs.to_ne_bytes()).unwrap();
+
+        assert!(record.is_legacy());
+        assert_eq!(record.mtime_ns, u64::max_value());
+    }
+}
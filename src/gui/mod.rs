@@ -3,10 +3,16 @@ use std::sync::{Arc, atomic::AtomicUsize};
 pub mod widgets;
 
 use self::widgets::main::Main;
-use super::searcher::Searcher;
+use super::indexer::CommitHandle;
+use super::searcher::Collections;
 
 use relm::Widget;
 
-pub fn spawn(searcher: Searcher, indexed_files: Arc<AtomicUsize>) {
-    Main::run((searcher, indexed_files)).unwrap();
+pub fn spawn(
+    collections: Collections,
+    indexed_files: Arc<AtomicUsize>,
+    commit_handles: Vec<CommitHandle>,
+    media_player: Vec<String>,
+) {
+    Main::run((collections, indexed_files, commit_handles, media_player)).unwrap();
 }
@@ -1,5 +1,5 @@
 use gtk::prelude::*;
-use relm::{connect, connect_stream, interval, ContainerWidget, Relm, Widget};
+use relm::{connect, connect_stream, interval, ContainerWidget, EventStream, Relm, Widget};
 use relm_attributes::widget;
 use relm_derive::Msg;
 use std::sync::{
@@ -7,18 +7,30 @@ use std::sync::{
     Arc,
 };
 
-use crate::searcher::{SearchResult, Searcher};
+use crate::indexer::CommitHandle;
+use crate::searcher::{Collections, SearchResult};
 
 #[derive(Msg)]
 pub enum Msg {
     Quit,
     Tick,
     Search(String),
+    CommitNow,
+    Optimize,
+    /// search for versions of text with this fingerprint
+    FindSimilar(u64),
+    /// a collection was ticked or unticked in the selector
+    SelectCollection(String, bool),
 }
 
 pub struct Model {
-    searcher: Searcher,
+    collections: Collections,
+    /// the collections searches go to
+    selected: Vec<String>,
     indexed_files: Arc<AtomicUsize>,
+    commit_handles: Vec<CommitHandle>,
+    media_player: Vec<String>,
+    stream: EventStream<Msg>,
     results: Vec<relm::Component<super::SearchResult>>,
 }
 
@@ -27,9 +39,11 @@ impl Main {
         self.clear();
 
         for result in results {
-            let child = self
-                .results_list
-                .add_widget::<super::SearchResult>((result.path, result.snippet));
+            let child = self.results_list.add_widget::<super::SearchResult>((
+                result,
+                self.model.media_player.clone(),
+                self.model.stream.clone(),
+            ));
 
             self.model.results.push(child);
         }
@@ -42,14 +56,53 @@ impl Main {
 
         self.model.results.clear();
     }
+
+    /// a check button for each collection, there's nothing to choose
+    /// between with only one
+    fn add_collection_selector(&self) {
+        let names = self.model.collections.names();
+        if names.len() < 2 {
+            return;
+        }
+
+        for name in names {
+            let button = gtk::CheckButton::new_with_label(&name);
+            button.set_active(true);
+
+            let stream = self.model.stream.clone();
+            button.connect_toggled(move |b| {
+                stream.emit(Msg::SelectCollection(name.clone(), b.get_active()))
+            });
+
+            self.collections_box.add(&button);
+        }
+
+        self.collections_box.show_all();
+    }
 }
 
 #[widget]
 impl Widget for Main {
-    fn model((searcher, indexed_files): (Searcher, Arc<AtomicUsize>)) -> Model {
+    fn init_view(&mut self) {
+        self.add_collection_selector();
+    }
+
+    fn model(
+        relm: &Relm<Self>,
+        (collections, indexed_files, commit_handles, media_player): (
+            Collections,
+            Arc<AtomicUsize>,
+            Vec<CommitHandle>,
+            Vec<String>,
+        ),
+    ) -> Model {
         Model {
-            searcher,
+            selected: collections.names(),
+            collections,
             indexed_files,
+            commit_handles,
+            media_player,
+            stream: relm.stream().clone(),
             results: Vec::new(),
         }
     }
@@ -62,15 +115,63 @@ impl Widget for Main {
         match event {
             Msg::Quit => gtk::main_quit(),
             Msg::Tick => {
+                let rebuilding = if self.model.collections.is_rebuilding(&self.model.selected) {
+                    " (rebuilding index)"
+                } else {
+                    ""
+                };
+
+                let segments = self.model.collections.segment_stats(&self.model.selected);
+
                 self.stats_label.set_text(&format!(
-                    "{} indexed files",
-                    self.model.indexed_files.load(Ordering::Relaxed)
+                    "{} indexed files in {} segments, {:.0}% deleted{}",
+                    self.model.indexed_files.load(Ordering::Relaxed),
+                    segments.segments,
+                    segments.deleted_ratio() * 100.0,
+                    rebuilding
                 ));
             }
+            Msg::CommitNow => {
+                for handle in &self.model.commit_handles {
+                    handle.commit_now();
+                }
+            }
+            Msg::Optimize => {
+                for handle in &self.model.commit_handles {
+                    handle.optimize();
+                }
+            }
+            // goes through the entry so the query can be seen and refined
+            Msg::FindSimilar(fingerprint) => self
+                .search_entry
+                .set_text(&format!("similar:{:x}", fingerprint)),
+            Msg::SelectCollection(name, selected) => {
+                self.model.selected.retain(|n| *n != name);
+                if selected {
+                    self.model.selected.push(name);
+                }
+
+                // search again with the same query
+                if let Some(text) = self.search_entry.get_text() {
+                    self.model.stream.emit(Msg::Search(text.to_string()));
+                }
+            }
             Msg::Search(s) => {
-                if let Some(results) = self.model.searcher.search(&s) {
+                let results = self.model.collections.search(&self.model.selected, &s);
+                if let Some(results) = results {
                     self.update_results(results);
                 }
+
+                let breakdown = self
+                    .model
+                    .collections
+                    .extension_counts(&self.model.selected, &s)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(ext, count)| format!("{}: {}", ext, count))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                self.breakdown_label.set_text(&breakdown);
             }
         }
     }
@@ -79,10 +180,17 @@ impl Widget for Main {
         gtk::Window {
             gtk::Box {
                 orientation: gtk::Orientation::Vertical,
+                #[name="search_entry"]
                 gtk::SearchEntry {
                     changed(entry) => Msg::Search(entry.get_text().unwrap().to_string()),
                     placeholder_text: Some("Search"),
                 },
+                #[name="collections_box"]
+                gtk::Box {
+                    orientation: gtk::Orientation::Horizontal,
+                },
+                #[name="breakdown_label"]
+                gtk::Label {},
                 #[name="results_list"]
                 gtk::Box {
                     orientation: gtk::Orientation::Vertical,
@@ -91,8 +199,19 @@ impl Widget for Main {
                         expand: true,
                     },
                 },
-                #[name="stats_label"]
-                gtk::Label {},
+                gtk::Box {
+                    orientation: gtk::Orientation::Horizontal,
+                    #[name="stats_label"]
+                    gtk::Label {},
+                    gtk::Button {
+                        label: "Make searchable now",
+                        clicked => Msg::CommitNow,
+                    },
+                    gtk::Button {
+                        label: "Optimize index",
+                        clicked => Msg::Optimize,
+                    },
+                },
             },
             delete_event(_, _) => (Msg::Quit, Inhibit(false)),
         },
@@ -1,32 +1,173 @@
 use gtk::prelude::*;
-use relm::{connect, Widget};
+use relm::{connect, EventStream, Widget};
 use relm_attributes::widget;
 use relm_derive::Msg;
 
+use chrono::NaiveDateTime;
+
+use super::main;
+use crate::index_subtitles;
+use crate::searcher;
+
 #[derive(Msg)]
 pub enum Msg {
     Open,
+    FindSimilar,
 }
 
 pub struct Model {
-    file_path: String,
-    snippet: String,
+    result: searcher::SearchResult,
+    media_player: Vec<String>,
+    /// for asking the main window to search for similar versions
+    main_stream: EventStream<main::Msg>,
+}
+
+impl SearchResult {
+    /// open the media belonging to a subtitle file at the offset of the cue,
+    /// returns false if there's no media to open
+    fn open_media(&self, offset: u64) -> bool {
+        let media = match index_subtitles::find_media_for(&self.model.result.path) {
+            Some(m) => m,
+            None => return false,
+        };
+
+        let args: Vec<String> = self
+            .model
+            .media_player
+            .iter()
+            .map(|a| {
+                a.replace("{file}", media.to_str().unwrap())
+                    .replace("{offset}", &offset.to_string())
+            })
+            .collect();
+
+        let (cmd, args) = match args.split_first() {
+            Some(split) => split,
+            None => return false,
+        };
+
+        if let Err(e) = std::process::Command::new(cmd).args(args).spawn() {
+            eprintln!("Couldn't start media player {}: {}", cmd, e);
+        }
+
+        true
+    }
+
+    /// the path of the result, along with where in the file the hit was
+    fn header(&self) -> String {
+        let result = &self.model.result;
+        let mut header = match &result.collection {
+            Some(collection) => format!("[{}] {}", collection, result.path),
+            None => result.path.clone(),
+        };
+
+        if let Some(offset) = result.media_offset {
+            header.push_str(&format!(" at {}", index_subtitles::format_offset(offset)));
+        }
+
+        if let Some(title) = &result.title {
+            header.push_str(&format!(" — {}", title));
+        }
+
+        if let Some(timestamp) = result.timestamp {
+            let time = NaiveDateTime::from_timestamp(timestamp as i64, 0);
+            header.push_str(&format!(" ({})", time.format("%Y-%m-%d %H:%M")));
+        }
+
+        if let Some(version) = &result.past_version {
+            let format = |t: u64| {
+                NaiveDateTime::from_timestamp(t as i64, 0)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            };
+
+            match version.modified {
+                Some(modified) => header.push_str(&format!(
+                    " [version from {}, replaced {}]",
+                    format(modified),
+                    format(version.replaced)
+                )),
+                None => header.push_str(&format!(
+                    " [past version, replaced {}]",
+                    format(version.replaced)
+                )),
+            }
+        }
+
+        match result.other_paths.len() {
+            0 => (),
+            1 => header.push_str(" (also at 1 other location)"),
+            n => header.push_str(&format!(" (also at {} other locations)", n)),
+        }
+
+        header
+    }
+
+    /// list the versions collapsed into this result, hidden until expanded
+    fn show_variants(&self) {
+        let variants = &self.model.result.variants;
+        if variants.is_empty() {
+            return;
+        }
+
+        let title = match variants.len() {
+            1 => "1 similar version".to_owned(),
+            n => format!("{} similar versions", n),
+        };
+
+        let expander = gtk::Expander::new(Some(title.as_str()));
+        let list = gtk::Label::new(Some(variants.join("\n").as_str()));
+        list.set_selectable(true);
+        expander.add(&list);
+
+        self.variants_box.add(&expander);
+        expander.show_all();
+    }
 }
 
 #[widget]
 impl Widget for SearchResult {
     fn init_view(&mut self) {
-        self.file_path_label.set_text(&self.model.file_path);
-        self.snippet_label.set_markup(&self.model.snippet);
+        self.file_path_label.set_text(&self.header());
+        self.snippet_label.set_markup(&self.model.result.snippet);
+        self.show_variants();
     }
 
-    fn model((file_path, snippet): (String, String)) -> Model {
-        Model { file_path, snippet: snippet.replace('\n', " ") }
+    fn model(
+        (mut result, media_player, main_stream): (
+            searcher::SearchResult,
+            Vec<String>,
+            EventStream<main::Msg>,
+        ),
+    ) -> Model {
+        result.snippet = result.snippet.replace('\n', " ");
+
+        Model {
+            result,
+            media_player,
+            main_stream,
+        }
     }
 
     fn update(&mut self, event: Msg) {
         match event {
-            Msg::Open => println!("Result opened: {}", self.model.file_path),
+            Msg::Open => {
+                let opened = match self.model.result.media_offset {
+                    Some(offset) => self.open_media(offset),
+                    None => false,
+                };
+
+                if !opened {
+                    println!("Result opened: {}", self.model.result.path);
+                }
+            }
+            Msg::FindSimilar => {
+                if let Some(fingerprint) = self.model.result.fingerprint {
+                    self.model
+                        .main_stream
+                        .emit(main::Msg::FindSimilar(fingerprint));
+                }
+            }
         }
     }
 
@@ -54,7 +195,17 @@ impl Widget for SearchResult {
                 gtk::Label {
                     selectable: true,
                     line_wrap: true,
-                }
+                },
+                #[name="variants_box"]
+                gtk::Box {
+                    orientation: gtk::Orientation::Vertical,
+                },
+            },
+            gtk::Button {
+                clicked => Msg::FindSimilar,
+                label: "Similar versions",
+                // too little text to have a fingerprint
+                sensitive: self.model.result.fingerprint.is_some(),
             },
             gtk::Button {
                 clicked => Msg::Open,
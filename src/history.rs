@@ -0,0 +1,65 @@
+// keeping the past versions of files in some roots searchable
+
+use serde_derive::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use super::config;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// a root whose files keep their past versions when they change or are
+/// deleted. with neither limit set every version is kept, and versions of
+/// deleted files are only removed by `keep_days`.
+///
+/// past versions only live in the index, so they're lost whenever the
+/// index is rebuilt for a new schema version
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryRoot {
+    pub root: PathBuf,
+    /// how many past versions of each file to keep
+    pub keep_versions: Option<usize>,
+    /// how many days to keep past versions for
+    pub keep_days: Option<u64>,
+}
+
+impl HistoryRoot {
+    /// whether to keep a past version, `newer` is how many versions of the
+    /// file are newer than it
+    pub fn keeps(&self, newer: usize, superseded_at: u64, now: u64) -> bool {
+        self.keep_versions.map_or(true, |n| newer < n)
+            && self.oldest_kept(now).map_or(true, |t| superseded_at >= t)
+    }
+
+    /// versions superseded before this are thrown away
+    pub fn oldest_kept(&self, now: u64) -> Option<u64> {
+        self.keep_days
+            .map(|days| now.saturating_sub(days * SECS_PER_DAY))
+    }
+}
+
+#[derive(Clone)]
+pub struct History {
+    roots: Vec<HistoryRoot>,
+}
+
+impl History {
+    pub fn new(config: &config::Config) -> History {
+        let mut roots = config.history_roots.clone();
+
+        // most specific root first
+        roots.sort_by_key(|r| std::cmp::Reverse(r.root.components().count()));
+
+        History { roots }
+    }
+
+    pub fn roots(&self) -> &[HistoryRoot] {
+        &self.roots
+    }
+
+    /// the history root a file is in, if it's in one
+    pub fn root_for<P: AsRef<Path>>(&self, path: P) -> Option<&HistoryRoot> {
+        self.roots
+            .iter()
+            .find(|r| path.as_ref().starts_with(&r.root))
+    }
+}
@@ -0,0 +1,115 @@
+// indexing the contents of zip archives
+
+use std::{
+    fs,
+    io::{BufReader, Read},
+    path::Path,
+};
+use zip::{result::ZipError, ZipArchive};
+
+use super::index_pdf;
+use super::secrets::{Extracted, Secrets};
+
+/// entries bigger than this are skipped, we hold them in memory
+const MAX_ENTRY_SIZE: u64 = 64 * 1024 * 1024;
+
+pub struct Entry {
+    /// path of the entry inside the archive
+    pub name: String,
+    pub content: Extracted,
+}
+
+fn read_all<R: Read>(reader: &mut R) -> Option<Vec<u8>> {
+    let mut buf = Vec::new();
+    reader.read_to_end(&mut buf).ok()?;
+    Some(buf)
+}
+
+/// extract every text or pdf file in a zip archive, encrypted entries are
+/// decrypted with passwords from the secret provider
+pub fn extract<P: AsRef<Path>>(path: P, secrets: &Secrets) -> Option<Vec<Entry>> {
+    let f = fs::File::open(&path).ok()?;
+    let mut archive = ZipArchive::new(BufReader::new(f)).ok()?;
+
+    // only ask the provider once we hit something encrypted
+    let mut passwords: Option<Vec<String>> = None;
+    let mut entries = Vec::new();
+
+    for i in 0..archive.len() {
+        let (name, size) = match archive.by_index_raw(i) {
+            Ok(f) if f.is_file() => (f.name().to_owned(), f.size()),
+            _ => continue,
+        };
+
+        let ext = Path::new(&name)
+            .extension()
+            .and_then(std::ffi::OsStr::to_str)
+            .map(str::to_ascii_lowercase);
+
+        let is_pdf = match ext.as_ref().map(String::as_str) {
+            Some("txt") | Some("org") | Some("md") | Some("rst") => false,
+            Some("pdf") => true,
+            _ => continue,
+        };
+
+        if size > MAX_ENTRY_SIZE {
+            continue;
+        }
+
+        let first_try = match archive.by_index(i) {
+            Ok(mut f) => Ok(read_all(&mut f)),
+            Err(e) => Err(e),
+        };
+
+        let (raw, encrypted) = match first_try {
+            Ok(raw) => (raw, false),
+            Err(ZipError::UnsupportedArchive(ZipError::PASSWORD_REQUIRED)) => {
+                let passwords = passwords.get_or_insert_with(|| secrets.passwords_for(&path));
+
+                // a wrong password can get past the header check, but then
+                // fails the crc check when read
+                let raw = passwords.iter().filter(|p| !p.is_empty()).find_map(|p| {
+                    match archive.by_index_decrypt(i, p.as_bytes()) {
+                        Ok(Ok(mut f)) => read_all(&mut f),
+                        _ => None,
+                    }
+                });
+
+                if raw.is_none() {
+                    eprintln!(
+                        "No password worked for {} in {}",
+                        name,
+                        path.as_ref().display()
+                    );
+                }
+
+                (raw, true)
+            }
+            Err(_) => continue,
+        };
+
+        let raw = match raw {
+            Some(r) => r,
+            None => continue,
+        };
+
+        let content = if is_pdf {
+            match index_pdf::extract(&raw, &path, secrets) {
+                Some(mut c) => {
+                    c.encrypted |= encrypted;
+                    c
+                }
+                None => continue,
+            }
+        } else {
+            Extracted {
+                text: String::from_utf8_lossy(&raw).into_owned(),
+                encrypted,
+            }
+        };
+
+        entries.push(Entry { name, content });
+    }
+
+    Some(entries)
+}
@@ -0,0 +1,245 @@
+// indexing calendar (.ics) and contact (.vcf) files
+//
+// both formats are made of `NAME;PARAM=VALUE:value` content lines grouped
+// into BEGIN/END components, each VEVENT, VTODO or VCARD becomes a record
+
+use chrono::{NaiveDate, NaiveDateTime};
+
+#[derive(Debug, Default)]
+pub struct Record {
+    pub summary: Option<String>,
+    pub description: Vec<String>,
+    pub location: Vec<String>,
+    pub attendees: Vec<String>,
+    /// start of an event or birthday of a contact, seconds since the epoch
+    pub start: Option<u64>,
+    /// end of an event or due date of a todo, seconds since the epoch
+    pub end: Option<u64>,
+    pub names: Vec<String>,
+    pub emails: Vec<String>,
+    pub phones: Vec<String>,
+}
+
+impl Record {
+    /// all the text of the record, used as the content of the document
+    pub fn content(&self) -> String {
+        self.summary
+            .iter()
+            .chain(&self.names)
+            .chain(&self.description)
+            .chain(&self.location)
+            .chain(&self.attendees)
+            .chain(&self.emails)
+            .chain(&self.phones)
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+struct ContentLine<'a> {
+    name: String,
+    params: Vec<(String, &'a str)>,
+    value: &'a str,
+}
+
+impl<'a> ContentLine<'a> {
+    fn parse(line: &'a str) -> Option<Self> {
+        // the name and params end at the first colon that isn't quoted
+        let mut in_quotes = false;
+        let split = line.char_indices().find(|&(_, c)| {
+            if c == '"' {
+                in_quotes = !in_quotes;
+            }
+            c == ':' && !in_quotes
+        })?;
+
+        let (head, value) = (&line[..split.0], &line[split.0 + 1..]);
+        let mut head = head.split(';');
+
+        // strip vcard groups: `item1.EMAIL` -> `EMAIL`
+        let name = head.next()?;
+        let name = name.rsplit('.').next()?.to_ascii_uppercase();
+
+        let params = head
+            .filter_map(|p| {
+                let mut kv = p.splitn(2, '=');
+                Some((
+                    kv.next()?.to_ascii_uppercase(),
+                    kv.next()?.trim_matches('"'),
+                ))
+            })
+            .collect();
+
+        Some(ContentLine {
+            name,
+            params,
+            value,
+        })
+    }
+
+    fn param(&self, name: &str) -> Option<&'a str> {
+        self.params.iter().find(|(k, _)| k == name).map(|&(_, v)| v)
+    }
+
+    fn text(&self) -> String {
+        unescape(self.value)
+    }
+}
+
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') | Some('N') => out.push('\n'),
+            Some(c) => out.push(c),
+            None => (),
+        }
+    }
+
+    out
+}
+
+/// undo line folding, continuation lines start with a space or tab
+fn unfold(content: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+
+    for line in content.lines() {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(last) = lines.last_mut() {
+                last.push_str(&line[1..]);
+                continue;
+            }
+        }
+
+        lines.push(line.to_owned());
+    }
+
+    lines
+}
+
+/// parse `20190612T100000Z`, `20190612T100000` or `20190612` into seconds
+/// since the epoch, times with a TZID are treated as UTC
+fn parse_datetime(value: &str) -> Option<u64> {
+    let value = value.trim().trim_end_matches('Z');
+
+    let datetime = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S")
+        .or_else(|_| NaiveDate::parse_from_str(value, "%Y%m%d").map(|d| d.and_hms(0, 0, 0)))
+        .or_else(|_| NaiveDate::parse_from_str(value, "%Y-%m-%d").map(|d| d.and_hms(0, 0, 0)))
+        .ok()?;
+
+    let timestamp = datetime.timestamp();
+    if timestamp < 0 {
+        None
+    } else {
+        Some(timestamp as u64)
+    }
+}
+
+/// an attendee such as `CN=Alice Smith:mailto:alice@example.com`
+/// becomes `Alice Smith <alice@example.com>`
+fn person(line: &ContentLine) -> String {
+    let value = line.text();
+    let address = value
+        .trim_start_matches("mailto:")
+        .trim_start_matches("MAILTO:");
+
+    match line.param("CN") {
+        Some(cn) => format!("{} <{}>", cn, address),
+        None => address.to_owned(),
+    }
+}
+
+/// `Smith;Alice;;Dr.;` -> `Dr. Alice Smith`
+fn structured_name(value: &str) -> String {
+    let parts: Vec<&str> = value.split(';').collect();
+    let order = [3, 1, 2, 0, 4];
+
+    order
+        .iter()
+        .filter_map(|&i| parts.get(i))
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn add_property(record: &mut Record, line: &ContentLine) {
+    match line.name.as_str() {
+        "SUMMARY" => record.summary = Some(line.text()),
+        "DESCRIPTION" | "COMMENT" | "NOTE" | "ORG" | "TITLE" | "CATEGORIES" => {
+            record.description.push(line.text().replace(';', " "))
+        }
+        "LOCATION" => record.location.push(line.text()),
+        "ADR" => record.location.push(
+            line.text()
+                .split(';')
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .collect::<Vec<_>>()
+                .join(", "),
+        ),
+        "ATTENDEE" | "ORGANIZER" => record.attendees.push(person(line)),
+        "DTSTART" | "BDAY" => record.start = parse_datetime(line.value),
+        "DTEND" | "DUE" => record.end = parse_datetime(line.value),
+        "FN" => record.names.insert(0, line.text()),
+        "N" | "NICKNAME" => record.names.push(structured_name(&line.text())),
+        "EMAIL" => record.emails.push(line.text()),
+        "TEL" => record
+            .phones
+            .push(line.text().trim_start_matches("tel:").to_owned()),
+        _ => (),
+    }
+}
+
+/// parse every VEVENT, VTODO and VCARD out of an ics or vcf file
+pub fn parse_records(content: &str) -> Vec<Record> {
+    let mut records = Vec::new();
+    // nesting of components inside the current record, such as a VALARM in a VEVENT
+    let mut current: Option<(Record, usize)> = None;
+
+    for line in unfold(content) {
+        let line = match ContentLine::parse(line.trim_end()) {
+            Some(l) => l,
+            None => continue,
+        };
+
+        match line.name.as_str() {
+            "BEGIN" => match current {
+                Some((_, ref mut depth)) => *depth += 1,
+                None => match line.value.trim().to_ascii_uppercase().as_str() {
+                    "VEVENT" | "VTODO" | "VCARD" => current = Some((Record::default(), 0)),
+                    _ => (),
+                },
+            },
+            "END" => {
+                let finished = match current {
+                    Some((_, ref mut depth)) if *depth > 0 => {
+                        *depth -= 1;
+                        false
+                    }
+                    Some(_) => true,
+                    None => false,
+                };
+
+                if finished {
+                    records.extend(current.take().map(|(r, _)| r));
+                }
+            }
+            _ => {
+                if let Some((ref mut record, 0)) = current {
+                    add_property(record, &line);
+                }
+            }
+        }
+    }
+
+    records
+}
@@ -0,0 +1,223 @@
+// indexing shell histories and chat exports, one document per command or message
+
+use chrono::DateTime;
+use serde_json::Value;
+use std::path::Path;
+
+#[derive(Debug, Default)]
+pub struct Entry {
+    pub text: String,
+    /// seconds since the epoch
+    pub timestamp: Option<u64>,
+    pub author: Option<String>,
+    pub channel: Option<String>,
+}
+
+/// zsh stores some bytes "metafied": 0x83 followed by the byte xor 32
+fn unmetafy(raw: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(raw.len());
+    let mut bytes = raw.iter();
+
+    while let Some(&b) = bytes.next() {
+        if b == 0x83 {
+            if let Some(&next) = bytes.next() {
+                out.push(next ^ 32);
+            }
+        } else {
+            out.push(b);
+        }
+    }
+
+    out
+}
+
+/// parse a zsh extended history line: `: 1560000000:0;ls -la`
+fn parse_zsh_extended(line: &str) -> Option<(u64, &str)> {
+    if !line.starts_with(": ") {
+        return None;
+    }
+
+    let rest = &line[2..];
+    let (meta, command) = rest.split_at(rest.find(';')?);
+    let timestamp = meta.split(':').next()?.trim().parse().ok()?;
+
+    Some((timestamp, &command[1..]))
+}
+
+/// parse a bash or zsh history file, handling zsh's extended format and
+/// bash's `#<timestamp>` lines (written when HISTTIMEFORMAT is set)
+pub fn parse_shell_history(raw: &[u8], shell: Option<&str>) -> Vec<Entry> {
+    // only zsh metafies, 0x83 is an ordinary utf-8 continuation byte anywhere else
+    let content = match shell {
+        Some("zsh") => String::from_utf8_lossy(&unmetafy(raw)).into_owned(),
+        _ => String::from_utf8_lossy(raw).into_owned(),
+    };
+
+    let mut entries = Vec::new();
+    let mut pending_timestamp = None;
+    let mut lines = content.lines();
+
+    while let Some(line) = lines.next() {
+        let mut command = line.to_owned();
+
+        // multi-line commands end each line with a backslash
+        while command.ends_with('\\') {
+            command.pop();
+            match lines.next() {
+                Some(next) => {
+                    command.push('\n');
+                    command.push_str(next);
+                }
+                None => break,
+            }
+        }
+
+        if command.starts_with('#') {
+            if let Ok(ts) = command[1..].parse() {
+                pending_timestamp = Some(ts);
+                continue;
+            }
+        }
+
+        let (timestamp, text) = match parse_zsh_extended(&command) {
+            Some((ts, text)) => (Some(ts), text.to_owned()),
+            None => (pending_timestamp.take(), command),
+        };
+
+        if text.trim().is_empty() {
+            continue;
+        }
+
+        entries.push(Entry {
+            text,
+            timestamp,
+            author: None,
+            channel: shell.map(str::to_owned),
+        });
+    }
+
+    entries
+}
+
+fn str_at<'a>(value: &'a Value, pointer: &str) -> Option<&'a str> {
+    value
+        .pointer(pointer)
+        .and_then(Value::as_str)
+        .filter(|s| !s.is_empty())
+}
+
+/// a slack export is a directory per channel holding a json array of
+/// messages per day
+fn parse_slack(messages: &[Value], channel: Option<&str>) -> Vec<Entry> {
+    messages
+        .iter()
+        .filter_map(|m| {
+            let text = str_at(m, "/text")?;
+            let timestamp = str_at(m, "/ts")
+                .and_then(|ts| ts.parse::<f64>().ok())
+                .map(|ts| ts as u64);
+            let author = str_at(m, "/user_profile/real_name")
+                .or_else(|| str_at(m, "/user_profile/name"))
+                .or_else(|| str_at(m, "/user_name"))
+                .or_else(|| str_at(m, "/user"));
+
+            Some(Entry {
+                text: text.to_owned(),
+                timestamp,
+                author: author.map(str::to_owned),
+                channel: channel.map(str::to_owned),
+            })
+        })
+        .collect()
+}
+
+/// a discord export (from DiscordChatExporter) is a single object per channel
+fn parse_discord(export: &Value) -> Vec<Entry> {
+    let channel = match (
+        str_at(export, "/guild/name"),
+        str_at(export, "/channel/name"),
+    ) {
+        (Some(guild), Some(channel)) => Some(format!("{}/{}", guild, channel)),
+        (None, Some(channel)) => Some(channel.to_owned()),
+        _ => None,
+    };
+
+    let messages = match export.pointer("/messages").and_then(Value::as_array) {
+        Some(m) => m,
+        None => return Vec::new(),
+    };
+
+    messages
+        .iter()
+        .filter_map(|m| {
+            let text = str_at(m, "/content")?;
+            let timestamp = str_at(m, "/timestamp")
+                .and_then(|ts| DateTime::parse_from_rfc3339(ts).ok())
+                .map(|ts| ts.timestamp() as u64);
+            let author = str_at(m, "/author/nickname").or_else(|| str_at(m, "/author/name"));
+
+            Some(Entry {
+                text: text.to_owned(),
+                timestamp,
+                author: author.map(str::to_owned),
+                channel: channel.clone(),
+            })
+        })
+        .collect()
+}
+
+/// parse a slack or discord json export, returns None if the file doesn't
+/// look like either
+pub fn parse_chat_export<P: AsRef<Path>>(path: P, content: &str) -> Option<Vec<Entry>> {
+    let value: Value = serde_json::from_str(content).ok()?;
+
+    match value {
+        Value::Array(ref messages)
+            if messages
+                .iter()
+                .any(|m| m.get("ts").is_some() && m.get("text").is_some()) =>
+        {
+            let channel = path
+                .as_ref()
+                .parent()
+                .and_then(Path::file_name)
+                .and_then(|n| n.to_str());
+
+            Some(parse_slack(messages, channel))
+        }
+        Value::Object(_) if value.get("messages").is_some() && value.get("channel").is_some() => {
+            Some(parse_discord(&value))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|e| e.text.as_str()).collect()
+    }
+
+    #[test]
+    fn bash_keeps_utf8() {
+        // `\xe3\x83\x86` is テ, its middle byte is zsh's meta marker
+        let raw = "#1560000000\necho テスト\nls\n".as_bytes();
+        let entries = parse_shell_history(raw, Some("bash"));
+
+        assert_eq!(texts(&entries), vec!["echo テスト", "ls"]);
+        assert_eq!(entries[0].timestamp, Some(1_560_000_000));
+        assert_eq!(entries[1].timestamp, None);
+    }
+
+    #[test]
+    fn zsh_is_unmetafied() {
+        // zsh metafies the last two bytes of テ, `\x83` and `\x86`
+        let raw = b": 1560000000:0;echo \xe3\x83\xa3\x83\xa6\n";
+        let entries = parse_shell_history(raw, Some("zsh"));
+
+        assert_eq!(texts(&entries), vec!["echo テ"]);
+        assert_eq!(entries[0].timestamp, Some(1_560_000_000));
+    }
+}
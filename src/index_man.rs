@@ -0,0 +1,256 @@
+// indexing roff man pages and GNU info pages
+
+use flate2::read::GzDecoder;
+use std::{fs, io::Read, path::Path};
+
+#[derive(Debug, Default)]
+pub struct Manual {
+    /// `ls` for a man page, `coreutils` for an info page
+    pub name: Option<String>,
+    /// the one line description, `ls - list directory contents`
+    pub summary: Option<String>,
+    pub synopsis: Option<String>,
+    /// man section (`1`, `3p`) or info dir section (`Basics`)
+    pub section: Option<String>,
+    /// the whole page with formatting removed
+    pub text: String,
+}
+
+/// read a file, decompressing it if it ends in `.gz`
+pub fn read_maybe_gz<P: AsRef<Path>>(path: P) -> Option<String> {
+    let raw = fs::read(&path).ok()?;
+
+    let is_gz = path
+        .as_ref()
+        .extension()
+        .map(|e| e == "gz")
+        .unwrap_or(false);
+
+    if !is_gz {
+        return Some(String::from_utf8_lossy(&raw).into_owned());
+    }
+
+    let mut content = Vec::new();
+    GzDecoder::new(&raw[..]).read_to_end(&mut content).ok()?;
+
+    Some(String::from_utf8_lossy(&content).into_owned())
+}
+
+/// translate a named roff character such as `em` or `co`
+fn named_char(name: &str) -> &'static str {
+    match name {
+        "em" | "en" | "hy" | "mi" => "-",
+        "lq" | "rq" | "dq" => "\"",
+        "oq" | "cq" | "aq" => "'",
+        "co" => "(c)",
+        "rg" => "(R)",
+        "bu" => "*",
+        "ti" => "~",
+        "ha" => "^",
+        "rs" => "\\",
+        _ => "",
+    }
+}
+
+/// take a `(xx`, `[name]` or single character escape argument
+fn escape_arg(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    match chars.next() {
+        Some('(') => chars.take(2).collect(),
+        Some('[') => chars.take_while(|&c| c != ']').collect(),
+        Some(c) => c.to_string(),
+        None => String::new(),
+    }
+}
+
+/// remove inline escapes (`\fB`, `\-`, `\(em`, ...) from a line of roff text
+fn strip_escapes(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            // comment until the end of the line
+            Some('"') => break,
+            Some('f') | Some('*') | Some('n') | Some('F') | Some('g') => {
+                escape_arg(&mut chars);
+            }
+            Some('s') => {
+                if let Some(&sign) = chars.peek() {
+                    if sign == '+' || sign == '-' {
+                        chars.next();
+                    }
+                }
+                while chars.peek().map(|c| c.is_ascii_digit()).unwrap_or(false) {
+                    chars.next();
+                }
+            }
+            Some('(') => {
+                let name: String = chars.by_ref().take(2).collect();
+                out.push_str(named_char(&name));
+            }
+            Some('[') => {
+                let name: String = chars.by_ref().take_while(|&c| c != ']').collect();
+                out.push_str(named_char(&name));
+            }
+            Some('e') | Some('\\') => out.push('\\'),
+            Some('-') => out.push('-'),
+            Some(' ') | Some('~') | Some('0') => out.push(' '),
+            Some('&') | Some('c') | Some('%') | Some('|') | Some('^') | Some(':') => (),
+            Some(c) => out.push(c),
+            None => (),
+        }
+    }
+
+    out
+}
+
+/// split macro arguments, honouring double quotes
+fn macro_args(args: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+
+    for c in args.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ' ' | '\t' if !in_quotes => {
+                if !current.is_empty() {
+                    out.push(std::mem::replace(&mut current, String::new()));
+                }
+            }
+            _ => current.push(c),
+        }
+    }
+
+    if !current.is_empty() {
+        out.push(current);
+    }
+
+    out
+}
+
+/// parse a man page written with either the man or mdoc macros
+pub fn parse_man_page(content: &str) -> Manual {
+    let mut manual = Manual::default();
+    let mut current_section = String::new();
+    let mut name_lines = Vec::new();
+    let mut synopsis_lines = Vec::new();
+    let mut lines = Vec::new();
+
+    for line in content.lines() {
+        let text = if line.starts_with('.') || line.starts_with('\'') {
+            let mut parts = line[1..].trim_start().splitn(2, |c| c == ' ' || c == '\t');
+            let request = parts.next().unwrap_or("");
+            let args = macro_args(parts.next().unwrap_or(""));
+
+            match request {
+                "TH" | "Dt" => {
+                    manual.name = args.get(0).map(|n| strip_escapes(n).to_lowercase());
+                    manual.section = args.get(1).map(|s| strip_escapes(s).to_lowercase());
+                    continue;
+                }
+                "SH" | "Sh" => {
+                    current_section = strip_escapes(&args.join(" ")).to_uppercase();
+                    lines.push(current_section.clone());
+                    continue;
+                }
+                // font changes, alternating ones join their arguments without spaces
+                "BR" | "RB" | "IR" | "RI" | "BI" | "IB" => args.join(""),
+                "B" | "I" | "SM" | "SB" | "SS" | "Ss" | "TP" | "IP" | "Nm" | "Nd" | "Fn" | "Fa"
+                | "Ar" | "Fl" | "Cm" | "Op" => {
+                    let text = args.join(" ");
+                    match request {
+                        "Nd" => format!("- {}", text),
+                        "Fl" => format!("-{}", text),
+                        _ => text,
+                    }
+                }
+                // everything else is formatting or a comment
+                _ => continue,
+            }
+        } else {
+            line.to_owned()
+        };
+
+        let text = strip_escapes(&text);
+        let text = text.trim();
+        if text.is_empty() {
+            continue;
+        }
+
+        match current_section.as_str() {
+            "NAME" => name_lines.push(text.to_owned()),
+            "SYNOPSIS" => synopsis_lines.push(text.to_owned()),
+            _ => (),
+        }
+
+        lines.push(text.to_owned());
+    }
+
+    if !name_lines.is_empty() {
+        manual.summary = Some(name_lines.join(" "));
+    }
+
+    if !synopsis_lines.is_empty() {
+        manual.synopsis = Some(synopsis_lines.join("\n"));
+    }
+
+    manual.text = lines.join("\n");
+    manual
+}
+
+/// parse an info page, node headers and the control characters between
+/// nodes are dropped
+pub fn parse_info_page(content: &str) -> Manual {
+    let mut manual = Manual::default();
+    let mut lines = Vec::new();
+    let mut after_separator = false;
+
+    for line in content.lines() {
+        if line.starts_with('\u{1f}') {
+            after_separator = true;
+            continue;
+        }
+
+        // the first line after a separator is the node header:
+        // `File: coreutils.info,  Node: ls invocation,  Next: ...`
+        if after_separator {
+            after_separator = false;
+
+            if line.starts_with("File: ") {
+                if manual.name.is_none() {
+                    manual.name = line[6..]
+                        .split(|c| c == ',' || c == ' ')
+                        .next()
+                        .map(|f| f.trim_end_matches(".info").to_owned());
+                }
+                continue;
+            }
+        }
+
+        if line.starts_with("INFO-DIR-SECTION ") {
+            manual.section = Some(line[17..].trim().to_owned());
+            continue;
+        }
+
+        // `* Coreutils: (coreutils).       Core GNU (file, text, shell) utilities.`
+        if manual.section.is_some() && manual.summary.is_none() && line.starts_with("* ") {
+            manual.summary = Some(line[2..].split_whitespace().collect::<Vec<_>>().join(" "));
+            continue;
+        }
+
+        if line.starts_with("START-INFO-DIR-ENTRY") || line.starts_with("END-INFO-DIR-ENTRY") {
+            continue;
+        }
+
+        lines.push(line);
+    }
+
+    manual.text = lines.join("\n");
+    manual
+}
@@ -1,3 +1,136 @@
 // indexing pdf files
 
-use pdf_extract;
+use lopdf;
+use pdf_extract::{self, PlainTextOutput};
+use std::{
+    fs,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    panic,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::secrets::{Extracted, Secrets};
+
+/// pdf_extract likes to panic on pdfs it doesn't understand, we don't want
+/// that taking down a worker
+fn catch<F: FnOnce() -> Option<String>>(f: F) -> Option<String> {
+    panic::catch_unwind(panic::AssertUnwindSafe(f))
+        .ok()
+        .and_then(|r| r)
+}
+
+fn text_of(doc: &lopdf::Document) -> Option<String> {
+    catch(|| {
+        let mut text = String::new();
+        {
+            let mut output = PlainTextOutput::new(&mut text);
+            pdf_extract::output_doc(doc, &mut output);
+        }
+        Some(text)
+    })
+}
+
+/// a copy of an encrypted pdf for qpdf to read, only we can read it and
+/// it's removed once dropped
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(raw: &[u8]) -> std::io::Result<Scratch> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let name = format!(
+            "sids-{}-{}.pdf",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        );
+        let scratch = Scratch(std::env::temp_dir().join(name));
+
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&scratch.0)?
+            .write_all(raw)?;
+
+        Ok(scratch)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// decrypt a pdf with qpdf, lopdf can't. the password goes through stdin
+/// so it doesn't show up in the process list. None if it's the wrong one
+fn decrypt(encrypted: &Path, password: &str) -> std::io::Result<Option<Vec<u8>>> {
+    let mut qpdf = Command::new("qpdf")
+        .arg("--password-file=-")
+        .arg("--decrypt")
+        .arg(encrypted)
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    if let Some(mut stdin) = qpdf.stdin.take() {
+        stdin.write_all(password.as_bytes())?;
+        stdin.write_all(b"\n")?;
+    }
+
+    let output = qpdf.wait_with_output()?;
+
+    // 3 means it worked but had warnings about the file
+    match output.status.code() {
+        Some(0) | Some(3) => Ok(Some(output.stdout)),
+        _ => Ok(None),
+    }
+}
+
+/// extract the text of a pdf, asking the secret provider for passwords if the
+/// pdf is encrypted. `path` is only used to look up passwords
+pub fn extract<P: AsRef<Path>>(raw: &[u8], path: P, secrets: &Secrets) -> Option<Extracted> {
+    let doc = lopdf::Document::load_from(raw).ok()?;
+
+    if doc.trailer.get(b"Encrypt").is_none() {
+        return text_of(&doc).map(Extracted::plain);
+    }
+
+    let path = path.as_ref();
+    let scratch = match Scratch::new(raw) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Couldn't copy {} to decrypt it: {}", path.display(), e);
+            return None;
+        }
+    };
+
+    for password in secrets.passwords_for(path) {
+        let decrypted = match decrypt(&scratch.0, &password) {
+            Ok(Some(d)) => d,
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("Couldn't run qpdf to decrypt {}: {}", path.display(), e);
+                return None;
+            }
+        };
+
+        let text = lopdf::Document::load_from(&decrypted[..])
+            .ok()
+            .and_then(|doc| text_of(&doc))?;
+
+        return Some(Extracted {
+            text,
+            encrypted: true,
+        });
+    }
+
+    eprintln!("No password worked for {}", path.display());
+
+    None
+}
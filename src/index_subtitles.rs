@@ -0,0 +1,106 @@
+// indexing subtitle and transcript files (.srt, .vtt)
+
+use std::path::{Path, PathBuf};
+
+/// extensions we'll look for next to a subtitle file when opening a result
+const MEDIA_EXTS: &[&str] = &[
+    "mkv", "mp4", "webm", "avi", "mov", "mp3", "m4a", "ogg", "opus", "wav", "flac",
+];
+
+#[derive(Debug)]
+pub struct Cue {
+    /// seconds from the start of the media
+    pub start: u64,
+    pub text: String,
+}
+
+/// parse a timestamp like `00:42:17,123` (srt) or `42:17.123` (vtt) into whole seconds
+fn parse_timestamp(ts: &str) -> Option<u64> {
+    let ts = ts.trim();
+    let whole = ts.split(|c| c == ',' || c == '.').next()?;
+
+    let mut secs = 0;
+    for part in whole.split(':') {
+        secs = secs * 60 + part.trim().parse::<u64>().ok()?;
+    }
+
+    Some(secs)
+}
+
+/// remove markup such as `<i>`, `<v Alice>` or `{\an8}` from cue text
+fn strip_tags(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut depth = 0;
+
+    for c in line.chars() {
+        match c {
+            '<' | '{' => depth += 1,
+            '>' | '}' if depth > 0 => depth -= 1,
+            _ if depth == 0 => out.push(c),
+            _ => (),
+        }
+    }
+
+    out
+}
+
+/// parse the cues out of an srt or vtt file, blocks without a timing line
+/// (the WEBVTT header, NOTE and STYLE blocks) are skipped
+pub fn parse_cues(content: &str) -> Vec<Cue> {
+    let content = content.trim_start_matches('\u{feff}').replace("\r\n", "\n");
+
+    content
+        .split("\n\n")
+        .filter_map(|block| {
+            let mut lines = block.lines().skip_while(|l| !l.contains("-->"));
+
+            let timing = lines.next()?;
+            let start = parse_timestamp(timing.split("-->").next()?)?;
+
+            let text = lines
+                .map(strip_tags)
+                .filter(|l| !l.trim().is_empty())
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            if text.is_empty() {
+                return None;
+            }
+
+            Some(Cue { start, text })
+        })
+        .collect()
+}
+
+/// format an offset in seconds as `hh:mm:ss`
+pub fn format_offset(secs: u64) -> String {
+    format!(
+        "{:02}:{:02}:{:02}",
+        secs / 3600,
+        (secs / 60) % 60,
+        secs % 60
+    )
+}
+
+/// find the media file a subtitle file belongs to, this is a file in the same
+/// directory with the same stem (`talk.en.srt` also matches `talk.mkv`)
+pub fn find_media_for<P: AsRef<Path>>(path: P) -> Option<PathBuf> {
+    let path = path.as_ref();
+    let dir = path.parent()?;
+    let mut stem = path.file_stem()?.to_str()?;
+
+    loop {
+        for ext in MEDIA_EXTS {
+            let candidate = dir.join(format!("{}.{}", stem, ext));
+            if candidate.exists() {
+                return Some(candidate);
+            }
+        }
+
+        // strip language tags and the like: `talk.en` -> `talk`
+        match stem.rfind('.') {
+            Some(idx) => stem = &stem[..idx],
+            None => return None,
+        }
+    }
+}
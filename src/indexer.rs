@@ -1,19 +1,36 @@
 // stuff for indexing files
 
-use crossbeam_channel::{Receiver, Sender};
+use crossbeam_channel::{select, Receiver, Sender};
 use failure::{Compat, Fail}; // oh no
-use num_cpus;
 use snafu::{ResultExt, Snafu};
 use std::{
     fs,
     io::{BufReader, Read},
-    path::Path,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
+use tantivy::merge_policy::{LogMergePolicy, MergePolicy, NoMergePolicy};
 use tantivy::{self, doc, schema::*};
 
+use super::analyzer;
+use super::commit_policy::{CommitPolicy, PendingWrites};
 use super::config;
-use super::file_collector::FileEntry;
-use super::once_every;
+use super::doc_writer::{DocWriter, WriteAction};
+use super::file_collector::{self, FileEntry, FileKind};
+use super::file_record::{self, ContentHash};
+use super::history::History;
+use super::index_archive;
+use super::index_calendar;
+use super::index_history;
+use super::index_man;
+use super::index_pdf;
+use super::index_subtitles;
+use super::last_modified_cache::{CacheUpdate, LastModifiedCache};
+use super::secrets::{EncryptedPolicy, Extracted, Secrets};
+use super::simhash;
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -26,31 +43,581 @@ pub enum Error {
         #[snafu(source(from(tantivy::TantivyError, tantivy::TantivyError::compat)))]
         source: Compat<tantivy::TantivyError>,
     },
+    #[snafu(display("Could not update the schema version at {}: {}", path.display(), source))]
+    SchemaVersionError {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("There's no index at {}", location.display()))]
+    NoSharedIndex { location: PathBuf },
+    #[snafu(display(
+        "The index at {} has schema version {}, it needs rebuilding with version {}",
+        location.display(),
+        version,
+        SCHEMA_VERSION
+    ))]
+    SharedIndexVersion { location: PathBuf, version: u64 },
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// bump this whenever `DocSchema` changes, existing indexes get rebuilt.
+/// changes to tokenizing only need `analyzer::ANALYZER_VERSION` bumped
+pub const SCHEMA_VERSION: u64 = 7;
+
+/// the file next to the indexes holding the version of the one in use
+fn version_file(location: &Path) -> PathBuf {
+    location.join("schema_version")
+}
+
+/// indexes made before we kept a version live in `index`
+fn index_dir(location: &Path, version: u64) -> PathBuf {
+    if version == 0 {
+        location.join("index")
+    } else {
+        location.join(format!("index-v{}", version))
+    }
+}
+
+/// the version of the index currently in use, if there is one
+fn current_version(location: &Path) -> Option<u64> {
+    match fs::read_to_string(version_file(location)) {
+        Ok(v) => v.trim().parse().ok(),
+        Err(_) if index_dir(location, 0).join("meta.json").exists() => Some(0),
+        Err(_) => None,
+    }
+}
+
+/// point at a new index, the rename makes the swap atomic
+fn set_current_version(location: &Path, version: u64) -> Result<()> {
+    let path = version_file(location);
+    let tmp = path.with_extension("tmp");
+
+    fs::write(&tmp, version.to_string())
+        .and_then(|_| fs::rename(&tmp, &path))
+        .context(SchemaVersionError { path })
+}
+
+/// open an index someone else maintains for searching. we can't rebuild it,
+/// so it has to have the current schema
+pub fn open_shared(
+    location: &Path,
+    config: &config::Config,
+) -> Result<(DocSchema, tantivy::Index)> {
+    match current_version(location) {
+        Some(SCHEMA_VERSION) => (),
+        Some(version) => {
+            return Err(Error::SharedIndexVersion {
+                location: location.to_path_buf(),
+                version,
+            })
+        }
+        None => {
+            return Err(Error::NoSharedIndex {
+                location: location.to_path_buf(),
+            })
+        }
+    }
+
+    let index = tantivy::Index::open_in_dir(index_dir(location, SCHEMA_VERSION))
+        .context(IndexTantivyError)?;
+    analyzer::register(&index, &config.analyzer);
+
+    Ok((DocSchema::new(), index))
+}
+
+/// remove indexes of other versions, these are either ones we've migrated
+/// away from or a rebuild that got interrupted
+fn remove_other_indexes(location: &Path, keep: u64) {
+    for version in (0..keep).chain(keep + 1..=SCHEMA_VERSION) {
+        let dir = index_dir(location, version);
+        if dir.exists() {
+            println!("Removing old index at {:?}", dir);
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+}
+
+/// an index with an older schema that keeps serving searches while the
+/// current schema's index is rebuilt next to it
+pub struct Migration {
+    old_index: tantivy::Index,
+    done: AtomicBool,
+}
+
+impl Migration {
+    pub fn old_index(&self) -> &tantivy::Index {
+        &self.old_index
+    }
+
+    /// whether the new index has been swapped in
+    pub fn is_done(&self) -> bool {
+        self.done.load(Ordering::Relaxed)
+    }
+}
+
+/// a text field run through our analyzer chain
+fn normalized_text() -> TextOptions {
+    let indexing = TextFieldIndexing::default()
+        .set_tokenizer(analyzer::TEXT_TOKENIZER)
+        .set_index_option(IndexRecordOption::WithFreqsAndPositions);
+
+    TextOptions::default().set_indexing_options(indexing)
+}
+
 #[derive(Clone)]
 pub struct DocSchema {
+    doc_id: Field,
     full_path: Field,
     filename: Field,
     content: Field,
+    media_offset: Field,
+    summary: Field,
+    description: Field,
+    location: Field,
+    attendee: Field,
+    name: Field,
+    email: Field,
+    phone: Field,
+    timestamp: Field,
+    end_timestamp: Field,
+    author: Field,
+    channel: Field,
+    synopsis: Field,
+    section: Field,
+    private_content: Field,
+    entry: Field,
+    mtime: Field,
+    size: Field,
+    extension: Field,
+    directory: Field,
+    owner: Field,
+    lang: Field,
+    simhash: Field,
+    simhash_band: Field,
+    past_of: Field,
+    superseded_at: Field,
+    /// content again, for each language we stem
+    stemmed_content: Vec<(&'static str, Field)>,
     schema: Schema,
 }
 
 impl DocSchema {
+    fn new() -> DocSchema {
+        let mut schema_builder = Schema::builder();
+
+        // identifies the file a document came from, every record extracted
+        // from a file shares it. this is what deletes and re-indexes match on
+        let doc_id = schema_builder.add_text_field("doc_id", STRING | STORED);
+        let full_path = schema_builder.add_text_field("full_path", STORED);
+        let filename = schema_builder.add_text_field("filename", normalized_text() | STORED);
+        let content = schema_builder.add_text_field("content", normalized_text() | STORED);
+        // seconds into the media for subtitle cues
+        let media_offset = schema_builder.add_u64_field("media_offset", STORED);
+
+        // fields of calendar and contact records
+        let summary = schema_builder.add_text_field("summary", TEXT | STORED);
+        let description = schema_builder.add_text_field("description", TEXT | STORED);
+        let location = schema_builder.add_text_field("location", TEXT | STORED);
+        let attendee = schema_builder.add_text_field("attendee", TEXT | STORED);
+        let name = schema_builder.add_text_field("name", TEXT | STORED);
+        let email = schema_builder.add_text_field("email", TEXT | STORED);
+        let phone = schema_builder.add_text_field("phone", TEXT | STORED);
+
+        // when a record happened (or started/ended), seconds since the epoch
+        let timestamp = schema_builder.add_u64_field("timestamp", INDEXED | STORED | FAST);
+        let end_timestamp = schema_builder.add_u64_field("end_timestamp", INDEXED | STORED);
+
+        // fields of shell history entries and chat messages
+        let author = schema_builder.add_text_field("author", TEXT | STORED);
+        let channel = schema_builder.add_text_field("channel", TEXT | STORED);
+
+        // fields of man and info pages, these also use name and summary
+        let synopsis = schema_builder.add_text_field("synopsis", TEXT | STORED);
+        let section = schema_builder.add_text_field("section", STRING | STORED);
+
+        // content of encrypted files which we may search but not keep the plaintext of
+        let private_content = schema_builder.add_text_field("private_content", normalized_text());
+        // path of a file inside an archive
+        let entry = schema_builder.add_text_field("entry", TEXT | STORED);
+
+        // metadata of the file itself, mtime is seconds since the epoch
+        let mtime = schema_builder.add_u64_field("mtime", INDEXED | STORED | FAST);
+        let size = schema_builder.add_u64_field("size", INDEXED | STORED | FAST);
+        let extension = schema_builder.add_facet_field("extension");
+        let directory = schema_builder.add_facet_field("directory");
+        let owner = schema_builder.add_facet_field("owner");
+
+        // the detected language of the content, which is also indexed
+        // into the stemmed field for that language
+        let lang = schema_builder.add_text_field("lang", STRING | STORED);
+        // a fingerprint of the content for finding other versions of it,
+        // indexed in bands so close fingerprints can be looked up
+        let simhash = schema_builder.add_u64_field("simhash", STORED);
+        let simhash_band = schema_builder.add_text_field("simhash_band", STRING);
+
+        // past versions of files in history roots are kept as documents of
+        // their own, with the path of the file they were a version of and
+        // when they were replaced (seconds since the epoch)
+        let past_of = schema_builder.add_text_field("past_of", STRING | STORED);
+        let superseded_at = schema_builder.add_u64_field("superseded_at", INDEXED | STORED);
+
+        let stemmed_content = analyzer::LANGUAGES
+            .iter()
+            .map(|(code, _)| {
+                let indexing = TextFieldIndexing::default()
+                    .set_tokenizer(&analyzer::stem_tokenizer(code))
+                    .set_index_option(IndexRecordOption::WithFreqsAndPositions);
+                let options = TextOptions::default().set_indexing_options(indexing);
+                let field = schema_builder.add_text_field(&format!("content_{}", code), options);
+
+                (*code, field)
+            })
+            .collect();
+
+        DocSchema {
+            doc_id,
+            full_path,
+            filename,
+            content,
+            media_offset,
+            summary,
+            description,
+            location,
+            attendee,
+            name,
+            email,
+            phone,
+            timestamp,
+            end_timestamp,
+            author,
+            channel,
+            synopsis,
+            section,
+            private_content,
+            entry,
+            mtime,
+            size,
+            extension,
+            directory,
+            owner,
+            lang,
+            simhash,
+            simhash_band,
+            past_of,
+            superseded_at,
+            stemmed_content,
+            schema: schema_builder.build(),
+        }
+    }
+
+    pub fn doc_id_term<P: AsRef<Path>>(&self, path: P) -> Term {
+        Term::from_field_text(self.doc_id, path.as_ref().to_str().unwrap())
+    }
+
+    pub fn doc_id(&self) -> Field {
+        self.doc_id
+    }
+
     pub fn full_path(&self) -> Field {
         self.full_path
     }
 
-    // pub fn filename(&self) -> Field {
-    //     self.filename
-    // }
+    pub fn filename(&self) -> Field {
+        self.filename
+    }
 
     pub fn content(&self) -> Field {
         self.content
     }
 
+    pub fn media_offset(&self) -> Field {
+        self.media_offset
+    }
+
+    pub fn summary(&self) -> Field {
+        self.summary
+    }
+
+    pub fn name(&self) -> Field {
+        self.name
+    }
+
+    pub fn timestamp(&self) -> Field {
+        self.timestamp
+    }
+
+    pub fn private_content(&self) -> Field {
+        self.private_content
+    }
+
+    pub fn entry(&self) -> Field {
+        self.entry
+    }
+
+    pub fn mtime(&self) -> Field {
+        self.mtime
+    }
+
+    pub fn size(&self) -> Field {
+        self.size
+    }
+
+    pub fn extension(&self) -> Field {
+        self.extension
+    }
+
+    pub fn directory(&self) -> Field {
+        self.directory
+    }
+
+    pub fn owner(&self) -> Field {
+        self.owner
+    }
+
+    pub fn simhash(&self) -> Field {
+        self.simhash
+    }
+
+    pub fn simhash_band(&self) -> Field {
+        self.simhash_band
+    }
+
+    pub fn past_of(&self) -> Field {
+        self.past_of
+    }
+
+    pub fn superseded_at(&self) -> Field {
+        self.superseded_at
+    }
+
+    /// the stemmed copies of content, one per language
+    pub fn stemmed_content(&self) -> impl Iterator<Item = Field> + '_ {
+        self.stemmed_content.iter().map(|(_, field)| *field)
+    }
+
+    /// all the text of a document we'd want to search
+    fn text_of(&self, doc: &Document) -> String {
+        doc.get_all(self.content)
+            .into_iter()
+            .chain(doc.get_all(self.private_content))
+            .filter_map(Value::text)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// tag a document with the language of its content and copy the content
+    /// into the stemmed field for it, `fallback` is used when there's too
+    /// little text to tell
+    fn add_language(&self, doc: &mut Document, fallback: Option<&'static str>) {
+        let text = self.text_of(doc);
+
+        let lang = match analyzer::detect_language(&text).or(fallback) {
+            Some(lang) => lang,
+            None => return,
+        };
+
+        doc.add_text(self.lang, lang);
+
+        if let Some((_, field)) = self.stemmed_content.iter().find(|(code, _)| *code == lang) {
+            doc.add_text(*field, &text);
+        }
+    }
+
+    /// tag the documents of a file with their languages
+    fn add_languages(&self, docs: &mut [Document]) {
+        // records like chat messages are often too short to tell the
+        // language of, so fall back to the language of the whole file
+        let file_lang = if docs.len() > 1 {
+            let text: Vec<String> = docs.iter().map(|d| self.text_of(d)).collect();
+            analyzer::detect_language(&text.join("\n"))
+        } else {
+            None
+        };
+
+        for doc in docs {
+            self.add_language(doc, file_lang);
+        }
+    }
+
+    fn add_fingerprint(&self, doc: &mut Document) {
+        let fingerprint = match simhash::fingerprint(&self.text_of(doc)) {
+            Some(fingerprint) => fingerprint,
+            None => return,
+        };
+
+        doc.add_u64(self.simhash, fingerprint);
+
+        for band in simhash::bands(fingerprint) {
+            doc.add_text(self.simhash_band, &band);
+        }
+    }
+
+    /// the facet of the directory a path is in, `/home/ben/notes` for
+    /// `/home/ben/notes/todo.org`
+    pub fn directory_facet<P: AsRef<Path>>(path: P) -> Facet {
+        use std::path::Component;
+
+        Facet::from_path(path.as_ref().components().filter_map(|c| match c {
+            Component::Normal(s) => s.to_str(),
+            _ => None,
+        }))
+    }
+
+    /// whether we keep everything in a document, so it can be rebuilt from
+    /// what's stored
+    pub fn is_stored(&self, doc: &Document) -> bool {
+        doc.get_first(self.private_content).is_none()
+    }
+
+    /// a stored document without the fields saying where it is or that are
+    /// derived from its content, which `place` puts back
+    pub fn without_location(&self, doc: &Document) -> Document {
+        let derived = [
+            self.doc_id,
+            self.full_path,
+            self.filename,
+            self.mtime,
+            self.size,
+            self.extension,
+            self.directory,
+            self.owner,
+            self.lang,
+            self.simhash,
+            self.past_of,
+            self.superseded_at,
+        ];
+
+        let mut stripped = Document::default();
+        for value in doc.field_values() {
+            if !derived.contains(&value.field()) {
+                stripped.add(value.clone());
+            }
+        }
+
+        stripped
+    }
+
+    /// the extension, directory and owner facets of some files
+    fn location_facets(&self, paths: &[PathBuf]) -> Vec<(Field, Facet)> {
+        // copies are often in the same directory or have the same owner
+        let mut facets: Vec<(Field, Facet)> = Vec::new();
+        for path in paths {
+            let mut add_facet = |field: Field, facet: Facet| {
+                if !facets.contains(&(field, facet.clone())) {
+                    facets.push((field, facet));
+                }
+            };
+
+            if let Some(ext) = path.extension().and_then(std::ffi::OsStr::to_str) {
+                add_facet(self.extension, Facet::from_path(vec![ext.to_lowercase()]));
+            }
+
+            if let Some(parent) = path.parent() {
+                add_facet(self.directory, Self::directory_facet(parent));
+            }
+
+            if let Some(owner) = file_collector::owner_of(path) {
+                add_facet(self.owner, Facet::from_path(vec![owner]));
+            }
+        }
+
+        facets
+    }
+
+    fn add_location(
+        &self,
+        doc: &mut Document,
+        id: &str,
+        paths: &[PathBuf],
+        facets: &[(Field, Facet)],
+    ) {
+        doc.add_text(self.doc_id, id);
+
+        for path in paths {
+            doc.add_text(self.full_path, path.to_str().unwrap());
+
+            if let Some(name) = path.file_name().and_then(std::ffi::OsStr::to_str) {
+                doc.add_text(self.filename, name);
+            }
+        }
+
+        for (field, facet) in facets {
+            doc.add_facet(*field, facet.clone());
+        }
+    }
+
+    /// fill in the documents of some content with `id` and the files it's
+    /// at. the mtime and size are those of the first file
+    pub fn place(&self, mut docs: Vec<Document>, id: &str, paths: &[PathBuf]) -> Vec<Document> {
+        let meta = paths.first().and_then(|p| fs::metadata(p).ok());
+        let facets = self.location_facets(paths);
+
+        for doc in &mut docs {
+            self.add_location(doc, id, paths, &facets);
+
+            if let Some(meta) = &meta {
+                let mtime = meta
+                    .modified()
+                    .ok()
+                    .and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs());
+
+                if let Some(mtime) = mtime {
+                    doc.add_u64(self.mtime, mtime);
+                }
+
+                doc.add_u64(self.size, meta.len());
+            }
+
+            self.add_fingerprint(doc);
+        }
+
+        self.add_languages(&mut docs);
+        docs
+    }
+
+    /// fill in the documents of a past version of a file. `modified` is when
+    /// the version was written and `superseded_at` when it was replaced
+    pub fn place_version(
+        &self,
+        mut docs: Vec<Document>,
+        id: &str,
+        path: &Path,
+        modified: Option<u64>,
+        superseded_at: u64,
+    ) -> Vec<Document> {
+        let paths = [path.to_path_buf()];
+        let facets = self.location_facets(&paths);
+
+        for doc in &mut docs {
+            self.add_location(doc, id, &paths, &facets);
+            doc.add_text(self.past_of, path.to_str().unwrap());
+            doc.add_u64(self.superseded_at, superseded_at);
+
+            if let Some(modified) = modified {
+                doc.add_u64(self.mtime, modified);
+            }
+
+            self.add_fingerprint(doc);
+        }
+
+        self.add_languages(&mut docs);
+        docs
+    }
+
+    pub fn past_of_term<P: AsRef<Path>>(&self, path: P) -> Term {
+        Term::from_field_text(self.past_of, path.as_ref().to_str().unwrap())
+    }
+
+    pub fn author(&self) -> Field {
+        self.author
+    }
+
+    pub fn channel(&self) -> Field {
+        self.channel
+    }
+
     // pub fn schema(&self) -> &Schema {
     //     &self.schema
     // }
@@ -59,34 +626,93 @@ impl DocSchema {
 pub struct DocIndexer {
     schema: DocSchema,
     indexer: tantivy::Index,
+    secrets: Arc<Secrets>,
     indexer_threads: Option<IndexerThreads>,
+    resources: config::IndexingResources,
+    commit: config::CommitConfig,
+    merge: config::MergeConfig,
+    requests: (Sender<WriterRequest>, Receiver<WriterRequest>),
+    modified_cache: LastModifiedCache,
+    history: History,
+    index_location: PathBuf,
+    migration: Option<Arc<Migration>>,
 }
 
 impl DocIndexer {
-    pub fn new(config: &config::Config) -> Result<DocIndexer> {
-        let mut schema_builder = Schema::builder();
+    pub fn new(config: &config::Config, modified_cache: LastModifiedCache) -> Result<DocIndexer> {
+        let schema = DocSchema::new();
 
-        let full_path = schema_builder.add_text_field("full_path", STORED);
-        let filename = schema_builder.add_text_field("filename", STRING | STORED);
-        let content = schema_builder.add_text_field("content", TEXT | STORED);
+        let location = &config.index_location;
+
+        let migration = match current_version(location) {
+            Some(SCHEMA_VERSION) => {
+                remove_other_indexes(location, SCHEMA_VERSION);
+                None
+            }
+            Some(old_version) => {
+                println!(
+                    "Index has schema version {}, rebuilding it for version {}",
+                    old_version, SCHEMA_VERSION
+                );
+
+                remove_other_indexes(location, old_version);
 
-        let schema = schema_builder.build();
+                let old_index = tantivy::Index::open_in_dir(index_dir(location, old_version))
+                    .context(IndexTantivyError)?;
+                analyzer::register(&old_index, &config.analyzer);
 
-        let mut indexer = Self::create_indexer(&schema, config)?;
+                Some(Arc::new(Migration {
+                    old_index,
+                    done: AtomicBool::new(false),
+                }))
+            }
+            None => {
+                // nothing to migrate from, just start using the new index
+                remove_other_indexes(location, SCHEMA_VERSION);
+                set_current_version(location, SCHEMA_VERSION)?;
+                None
+            }
+        };
+
+        let mut indexer = Self::create_indexer(&schema.schema, config)?;
         indexer.set_default_multithread_executor();
 
         Ok(DocIndexer {
-            schema: DocSchema {
-                full_path,
-                filename,
-                content,
-                schema,
-            },
+            schema,
             indexer,
+            secrets: Arc::new(Secrets::new(config)),
             indexer_threads: None,
+            resources: config.indexing.resources(),
+            commit: config.commit.clone(),
+            merge: config.merge.clone(),
+            requests: crossbeam_channel::unbounded(),
+            modified_cache,
+            history: History::new(config),
+            index_location: location.clone(),
+            migration,
         })
     }
 
+    /// the index being migrated away from, if we're rebuilding
+    pub fn migration(&self) -> Option<&Arc<Migration>> {
+        self.migration.as_ref()
+    }
+
+    /// swap in the rebuilt index, everything sent to the indexer must be
+    /// flushed before calling this
+    pub fn finish_migration(&mut self) -> Result<()> {
+        if let Some(migration) = self.migration.take() {
+            set_current_version(&self.index_location, SCHEMA_VERSION)?;
+            migration.done.store(true, Ordering::Relaxed);
+
+            // the old index is still mapped by the searcher, it gets
+            // removed next time we start
+            println!("Finished rebuilding the index");
+        }
+
+        Ok(())
+    }
+
     pub fn schema(&self) -> &DocSchema {
         &self.schema
     }
@@ -96,28 +722,73 @@ impl DocIndexer {
     }
 
     fn create_indexer(schema: &Schema, config: &config::Config) -> Result<tantivy::Index> {
-        let index_folder = config.index_location.join("index");
+        let index_folder = index_dir(&config.index_location, SCHEMA_VERSION);
         std::fs::create_dir_all(&index_folder).unwrap();
         let dir = tantivy::directory::MmapDirectory::open(&index_folder).context(IndexDirError)?;
 
         let index =
             tantivy::Index::open_or_create(dir, schema.clone()).context(IndexTantivyError)?;
+        analyzer::register(&index, &config.analyzer);
 
         Ok(index)
     }
 
     pub fn spawn_workers(&mut self) -> Result<()> {
-        self.indexer_threads = Some(IndexerThreads::new(&self.schema, &self.indexer)?);
+        self.indexer_threads = Some(IndexerThreads::new(
+            &self.schema,
+            &self.indexer,
+            &self.secrets,
+            &self.resources,
+            CommitPolicy::new(&self.commit),
+            self.requests.1.clone(),
+            self.doc_writer()?,
+        )?);
 
         Ok(())
     }
 
+    fn doc_writer(&self) -> Result<DocWriter> {
+        let writer = self
+            .indexer
+            .writer_with_num_threads(
+                self.resources.writer_threads,
+                self.resources.writer_heap_bytes,
+            )
+            .context(IndexTantivyError)?;
+        writer.set_merge_policy(merge_policy(&self.merge));
+
+        let reader = self
+            .indexer
+            .reader_builder()
+            .reload_policy(tantivy::ReloadPolicy::Manual)
+            .try_into()
+            .context(IndexTantivyError)?;
+
+        Ok(DocWriter::new(
+            writer,
+            reader,
+            self.schema.clone(),
+            self.modified_cache.clone(),
+            self.history.clone(),
+        ))
+    }
+
     pub fn close(&mut self) {
         if let Some(workers) = self.indexer_threads.take() {
             workers.join();
         }
     }
 
+    pub fn commit_handle(&self) -> CommitHandle {
+        CommitHandle(self.requests.0.clone())
+    }
+
+    /// wait for every job sent so far to be written and committed
+    pub fn flush(&mut self) -> Result<()> {
+        self.close();
+        self.spawn_workers()
+    }
+
     pub fn add_job(&self, req: IndexRequest) {
         self.indexer_threads
             .as_ref()
@@ -126,15 +797,94 @@ impl DocIndexer {
     }
 }
 
-enum IndexCommand {
-    ReIndex(Term, Document),
-    Index(Document),
-    Delete(Term),
+struct IndexCommand {
+    path: PathBuf,
+    action: WriteAction,
+    /// what the modified cache gets told once this is committed
+    update: CacheUpdate,
 }
 
 #[derive(Debug)]
 pub struct IndexRequest(pub FileEntry);
 
+/// what the writer thread can be asked to do besides writing documents
+pub enum WriterRequest {
+    Commit,
+    Optimize,
+}
+
+/// makes the index writer commit whatever's pending straight away, or
+/// compact the index
+#[derive(Clone)]
+pub struct CommitHandle(Sender<WriterRequest>);
+
+impl CommitHandle {
+    pub fn commit_now(&self) {
+        let _ = self.0.send(WriterRequest::Commit);
+    }
+
+    /// commit, then merge every segment into one and drop deleted documents
+    pub fn optimize(&self) {
+        let _ = self.0.send(WriterRequest::Optimize);
+    }
+}
+
+/// how many segments an index is split into and how much of it is deleted
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SegmentStats {
+    pub segments: usize,
+    pub docs: u64,
+    pub deleted_docs: u64,
+}
+
+impl SegmentStats {
+    pub fn of(searcher: &tantivy::Searcher) -> SegmentStats {
+        let mut stats = SegmentStats::default();
+
+        for segment in searcher.segment_readers() {
+            stats.segments += 1;
+            stats.docs += u64::from(segment.num_docs());
+            stats.deleted_docs += u64::from(segment.num_deleted_docs());
+        }
+
+        stats
+    }
+
+    /// the stats of several indexes together
+    pub fn add(self, other: SegmentStats) -> SegmentStats {
+        SegmentStats {
+            segments: self.segments + other.segments,
+            docs: self.docs + other.docs,
+            deleted_docs: self.deleted_docs + other.deleted_docs,
+        }
+    }
+
+    /// how much of what's stored is deleted documents, which only go
+    /// when their segment is merged
+    pub fn deleted_ratio(&self) -> f64 {
+        let total = self.docs + self.deleted_docs;
+
+        if total == 0 {
+            0.0
+        } else {
+            self.deleted_docs as f64 / total as f64
+        }
+    }
+}
+
+fn merge_policy(config: &config::MergeConfig) -> Box<dyn MergePolicy> {
+    match config.policy {
+        config::MergePolicyKind::Log => {
+            let mut policy = LogMergePolicy::default();
+            policy.set_min_merge_size(config.min_merge_segments);
+            policy.set_min_layer_size(config.min_layer_docs);
+            policy.set_level_log_size(config.level_log_size);
+            Box::new(policy)
+        }
+        config::MergePolicyKind::None => Box::new(NoMergePolicy),
+    }
+}
+
 //  the system looks a bit like this
 //
 //
@@ -149,50 +899,119 @@ struct IndexerWorker {
     i_recv: Receiver<IndexRequest>,
     d_send: Sender<IndexCommand>,
     schema: DocSchema,
+    secrets: Arc<Secrets>,
+    /// for finding content that's already indexed
+    index_reader: tantivy::IndexReader,
+    /// for telling whether that content came from the current extractors
+    modified_cache: LastModifiedCache,
 }
 impl IndexerWorker {
     fn go(self) {
         for IndexRequest(file) in &self.i_recv {
             use super::file_collector::CollectorOp;
 
+            let path = file.full_path().to_path_buf();
+
             if let CollectorOp::Delete = file.operation() {
-                let _ = self.d_send.send(IndexCommand::Delete(Term::from_field_text(
-                    self.schema.full_path,
-                    file.full_path().to_str().unwrap(),
-                )));
+                let _ = self.d_send.send(IndexCommand {
+                    path,
+                    action: WriteAction::Remove,
+                    update: CacheUpdate::Deleted,
+                });
                 continue;
             }
 
-            if let Some(content) = match file.file_ext() {
-                "txt" | "org" | "md" | "rst" => self.index_text_doc(file.full_path()),
-                _ext => {
-                    // eprintln!("Unknown ext: {}", ext);
-                    continue;
+            let (stat, kind) = match (file.stat(), file.kind()) {
+                (Some(stat), Some(kind)) => (*stat, kind),
+                _ => continue,
+            };
+
+            let hash = file_record::hash_file(&path).ok();
+            let indexed = CacheUpdate::Indexed {
+                stat,
+                hash,
+                extractor: kind.extractor(),
+            };
+
+            // copies of content we've already indexed, including files that
+            // have been moved, don't need extracting again. re-indexes always
+            // extract, and so does content from an older extractor or
+            // analyzer. writing it replaces it for every copy
+            let reindex = match file.operation() {
+                CollectorOp::ReIndex => true,
+                _ => false,
+            };
+            let shareable = |hash: &ContentHash| {
+                self.is_indexed(hash) && self.modified_cache.is_current(hash, kind.extractor())
+            };
+            let command = match hash {
+                Some(hash) if !reindex && shareable(&hash) => IndexCommand {
+                    path,
+                    action: WriteAction::Share { hash },
+                    update: indexed,
+                },
+                _ => {
+                    let docs = match kind {
+                        FileKind::Text => self
+                            .index_text_doc(&path)
+                            .map(|content| vec![doc!(self.schema.content => content)]),
+                        FileKind::Subtitles => self.index_subtitles_doc(&path),
+                        FileKind::Calendar => self.index_calendar_doc(&path),
+                        FileKind::ShellHistory => self.index_shell_history_doc(&file),
+                        FileKind::ChatExport => self.index_chat_export_doc(&path),
+                        FileKind::ManPage => self.index_manual_doc(&path, false),
+                        FileKind::InfoPage => self.index_manual_doc(&path, true),
+                        FileKind::Pdf => self.index_pdf_doc(&path),
+                        FileKind::Archive => self.index_archive_doc(&path),
+                    };
+
+                    // if extraction failed we still remove whatever was
+                    // indexed before, stale content is worse than none. the
+                    // cache forgets the file so we try again next time
+                    match docs {
+                        Some(docs) => IndexCommand {
+                            path,
+                            action: WriteAction::Write { hash, docs },
+                            update: indexed,
+                        },
+                        None => IndexCommand {
+                            path,
+                            action: WriteAction::Remove,
+                            update: CacheUpdate::Failed,
+                        },
+                    }
                 }
-            } {
-                let doc = doc!(
-                    self.schema.full_path => file.full_path().to_str().unwrap(),
-                    self.schema.filename => file.file_name(),
-                    self.schema.content => content,
-                );
+            };
+
+            let _ = self.d_send.send(command);
 
-                let command = match file.operation() {
-                    CollectorOp::ReIndex => IndexCommand::ReIndex(
-                        Term::from_field_text(
-                            self.schema.full_path,
-                            file.full_path().to_str().unwrap(),
-                        ),
-                        doc,
-                    ),
-                    CollectorOp::Index => IndexCommand::Index(doc),
-                    _ => unreachable!(),
-                };
-
-                let _ = self.d_send.send(command);
+            // the old path goes after the new one is listed, so content
+            // only at the old path isn't thrown away in between
+            if let Some(from) = file.moved_from() {
+                let _ = self.d_send.send(IndexCommand {
+                    path: from.to_path_buf(),
+                    action: WriteAction::Remove,
+                    update: CacheUpdate::Deleted,
+                });
             }
         }
     }
 
+    /// whether there are documents for some content in the index
+    fn is_indexed(&self, hash: &ContentHash) -> bool {
+        use tantivy::collector::Count;
+        use tantivy::query::TermQuery;
+
+        let id = file_record::content_id(hash);
+        let query = TermQuery::new(self.schema.doc_id_term(&id), IndexRecordOption::Basic);
+
+        self.index_reader
+            .searcher()
+            .search(&query, &Count)
+            .map(|count| count > 0)
+            .unwrap_or(false)
+    }
+
     fn index_text_doc<P: AsRef<Path>>(&self, file: P) -> Option<String> {
         // TODO: eventually keep track of errors
         let f = fs::File::open(&file).ok()?;
@@ -205,6 +1024,186 @@ impl IndexerWorker {
 
         Some(content)
     }
+
+    /// a document for text that may have needed a password to get at,
+    /// where it goes depends on the policy of the root the file is in
+    fn protected_doc<P: AsRef<Path>>(&self, file: P, extracted: Extracted) -> Option<Document> {
+        let policy = if extracted.encrypted {
+            self.secrets.policy_for(file)
+        } else {
+            EncryptedPolicy::Store
+        };
+
+        match policy {
+            EncryptedPolicy::Skip => None,
+            EncryptedPolicy::IndexOnly => Some(doc!(self.schema.private_content => extracted.text)),
+            EncryptedPolicy::Store => Some(doc!(self.schema.content => extracted.text)),
+        }
+    }
+
+    fn index_pdf_doc<P: AsRef<Path>>(&self, file: P) -> Option<Vec<Document>> {
+        let raw = fs::read(&file).ok()?;
+        let extracted = index_pdf::extract(&raw, &file, &self.secrets)?;
+
+        Some(self.protected_doc(file, extracted).into_iter().collect())
+    }
+
+    /// one document per file in the archive
+    fn index_archive_doc<P: AsRef<Path>>(&self, file: P) -> Option<Vec<Document>> {
+        let entries = index_archive::extract(&file, &self.secrets)?;
+
+        Some(
+            entries
+                .into_iter()
+                .filter_map(|entry| {
+                    let mut doc = self.protected_doc(&file, entry.content)?;
+                    doc.add_text(self.schema.entry, &entry.name);
+                    Some(doc)
+                })
+                .collect(),
+        )
+    }
+
+    /// one document per cue, so a hit can point at the time it was said
+    fn index_subtitles_doc<P: AsRef<Path>>(&self, file: P) -> Option<Vec<Document>> {
+        let content = self.index_text_doc(file)?;
+
+        Some(
+            index_subtitles::parse_cues(&content)
+                .into_iter()
+                .map(|cue| {
+                    doc!(
+                        self.schema.content => cue.text,
+                        self.schema.media_offset => cue.start,
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    /// one document per event, todo or contact
+    fn index_calendar_doc<P: AsRef<Path>>(&self, file: P) -> Option<Vec<Document>> {
+        let content = self.index_text_doc(file)?;
+
+        Some(
+            index_calendar::parse_records(&content)
+                .into_iter()
+                .map(|record| {
+                    let mut doc = doc!(self.schema.content => record.content());
+
+                    if let Some(summary) = &record.summary {
+                        doc.add_text(self.schema.summary, summary);
+                    }
+
+                    let text_fields = [
+                        (self.schema.description, &record.description),
+                        (self.schema.location, &record.location),
+                        (self.schema.attendee, &record.attendees),
+                        (self.schema.name, &record.names),
+                        (self.schema.email, &record.emails),
+                        (self.schema.phone, &record.phones),
+                    ];
+
+                    for (field, values) in text_fields.iter() {
+                        for value in values.iter() {
+                            doc.add_text(*field, value);
+                        }
+                    }
+
+                    if let Some(start) = record.start {
+                        doc.add_u64(self.schema.timestamp, start);
+                    }
+
+                    if let Some(end) = record.end {
+                        doc.add_u64(self.schema.end_timestamp, end);
+                    }
+
+                    doc
+                })
+                .collect(),
+        )
+    }
+
+    /// one document per command
+    fn index_shell_history_doc(&self, file: &FileEntry) -> Option<Vec<Document>> {
+        let raw = fs::read(file.full_path()).ok()?;
+
+        let shell = match file.file_name() {
+            ".bash_history" => Some("bash"),
+            ".zsh_history" | ".histfile" => Some("zsh"),
+            _ => None,
+        };
+
+        let owner = file.owner();
+
+        let mut entries = index_history::parse_shell_history(&raw, shell);
+        for entry in &mut entries {
+            entry.author = owner.clone();
+        }
+
+        Some(self.history_entries_to_docs(entries))
+    }
+
+    /// one document per message, None if the file isn't a chat export
+    fn index_chat_export_doc<P: AsRef<Path>>(&self, file: P) -> Option<Vec<Document>> {
+        let content = self.index_text_doc(&file)?;
+        let entries = index_history::parse_chat_export(&file, &content)?;
+
+        Some(self.history_entries_to_docs(entries))
+    }
+
+    fn index_manual_doc<P: AsRef<Path>>(&self, file: P, is_info: bool) -> Option<Vec<Document>> {
+        let content = index_man::read_maybe_gz(file)?;
+
+        let manual = if is_info {
+            index_man::parse_info_page(&content)
+        } else {
+            index_man::parse_man_page(&content)
+        };
+
+        let mut doc = doc!(self.schema.content => manual.text);
+
+        if let Some(name) = &manual.name {
+            doc.add_text(self.schema.name, name);
+        }
+
+        if let Some(summary) = &manual.summary {
+            doc.add_text(self.schema.summary, summary);
+        }
+
+        if let Some(synopsis) = &manual.synopsis {
+            doc.add_text(self.schema.synopsis, synopsis);
+        }
+
+        if let Some(section) = &manual.section {
+            doc.add_text(self.schema.section, section);
+        }
+
+        Some(vec![doc])
+    }
+
+    fn history_entries_to_docs(&self, entries: Vec<index_history::Entry>) -> Vec<Document> {
+        entries
+            .into_iter()
+            .map(|entry| {
+                let mut doc = doc!(self.schema.content => entry.text);
+
+                if let Some(timestamp) = entry.timestamp {
+                    doc.add_u64(self.schema.timestamp, timestamp);
+                }
+
+                if let Some(author) = &entry.author {
+                    doc.add_text(self.schema.author, author);
+                }
+
+                if let Some(channel) = &entry.channel {
+                    doc.add_text(self.schema.channel, channel);
+                }
+
+                doc
+            })
+            .collect()
+    }
 }
 pub struct IndexerThreads {
     doc_processor_threads: Vec<std::thread::JoinHandle<()>>,
@@ -213,24 +1212,36 @@ pub struct IndexerThreads {
 }
 
 impl IndexerThreads {
-    pub fn new(schema: &DocSchema, indexer: &tantivy::Index) -> Result<Self> {
-        // TODO: make this configurable
-
-        let num_cpus = num_cpus::get();
-        let (index_send, index_recv) = crossbeam_channel::bounded(num_cpus);
-        let (doc_send, doc_recv) = crossbeam_channel::bounded(num_cpus);
+    pub fn new(
+        schema: &DocSchema,
+        indexer: &tantivy::Index,
+        secrets: &Arc<Secrets>,
+        resources: &config::IndexingResources,
+        commit_policy: CommitPolicy,
+        requests: Receiver<WriterRequest>,
+        doc_writer: DocWriter,
+    ) -> Result<Self> {
+        let (index_send, index_recv) = crossbeam_channel::bounded(resources.queue_depth);
+        let (doc_send, doc_recv) = crossbeam_channel::bounded(resources.queue_depth);
+        let index_reader = indexer.reader().context(IndexTantivyError)?;
 
-        let doc_processor_threads = (0..num_cpus)
+        let doc_processor_threads = (0..resources.workers)
             .map(|_| {
                 let i_recv = index_recv.clone();
                 let d_send = doc_send.clone();
                 let t_schema = schema.clone();
+                let t_secrets = secrets.clone();
+                let t_reader = index_reader.clone();
+                let t_cache = doc_writer.modified_cache().clone();
 
                 Ok(std::thread::spawn(move || {
                     let worker = IndexerWorker {
                         i_recv,
                         d_send,
                         schema: t_schema,
+                        secrets: t_secrets,
+                        index_reader: t_reader,
+                        modified_cache: t_cache,
                     };
 
                     worker.go()
@@ -238,10 +1249,8 @@ impl IndexerThreads {
             })
             .collect::<Result<_>>()?;
 
-        let doc_writer = indexer.writer(500_000_000).context(IndexTantivyError)?;
-
         let doc_consumer_thread = std::thread::spawn(move || {
-            Self::do_doc_writes(doc_writer, doc_recv);
+            Self::do_doc_writes(doc_writer, doc_recv, requests, commit_policy);
         });
 
         Ok(IndexerThreads {
@@ -251,28 +1260,86 @@ impl IndexerThreads {
         })
     }
 
-    fn do_doc_writes(mut writer: tantivy::IndexWriter, d_recv: Receiver<IndexCommand>) {
-        for (command, should_commit) in d_recv.iter().zip(once_every::OnceEvery::new(1000)) {
-            let (revoke_doc, doc) = match command {
-                IndexCommand::ReIndex(revoke_doc, doc) => (Some(revoke_doc), Some(doc)),
-                IndexCommand::Index(doc) => (None, Some(doc)),
-                IndexCommand::Delete(revoke_doc) => (Some(revoke_doc), None),
-            };
+    fn do_doc_writes(
+        mut writer: DocWriter,
+        d_recv: Receiver<IndexCommand>,
+        requests: Receiver<WriterRequest>,
+        commit_policy: CommitPolicy,
+    ) {
+        let modified_cache = writer.modified_cache().clone();
+        let mut pending = PendingWrites::new(commit_policy);
+        let mut uncommitted = Vec::new();
 
-            if let Some(revoke_doc) = revoke_doc {
-                writer.delete_term(revoke_doc);
-            }
+        // versions can expire while their files stay the same
+        let (docs, bytes) = writer.prune_expired();
+        if docs > 0 {
+            pending.record(docs, bytes);
+        }
+
+        loop {
+            // wake up when a time based commit is due
+            let due = pending
+                .time_until_due()
+                .map(crossbeam_channel::after)
+                .unwrap_or_else(crossbeam_channel::never);
+
+            select! {
+                recv(d_recv) -> command => match command {
+                    Ok(IndexCommand { path, action, update }) => {
+                        let update = match writer.apply(&path, action) {
+                            Some((docs, bytes)) => {
+                                pending.record(docs, bytes);
+                                update
+                            }
+                            None => CacheUpdate::Failed,
+                        };
+                        uncommitted.push((path, update));
+                    }
+                    Err(_) => break,
+                },
+                recv(requests) -> request => match request {
+                    Ok(WriterRequest::Optimize) => {
+                        // so what's pending gets merged too
+                        Self::commit(&mut writer, &modified_cache, &mut uncommitted);
+                        pending.reset();
 
-            if let Some(doc) = doc {
-                writer.add_document(doc);
+                        if let Err(e) = writer.optimize() {
+                            eprintln!("Couldn't optimize the index: {}", e);
+                        }
+                    }
+                    _ => pending.force(),
+                },
+                recv(due) -> _ => (),
             }
 
-            if should_commit {
-                let _ = writer.commit();
+            if pending.should_commit() {
+                Self::commit(&mut writer, &modified_cache, &mut uncommitted);
+                pending.reset();
             }
         }
 
-        let _ = writer.commit();
+        Self::commit(&mut writer, &modified_cache, &mut uncommitted);
+    }
+
+    /// commit the index, then tell the modified cache about the files that
+    /// are now in it
+    fn commit(
+        writer: &mut DocWriter,
+        modified_cache: &LastModifiedCache,
+        uncommitted: &mut Vec<(PathBuf, CacheUpdate)>,
+    ) {
+        if let Err(e) = writer.commit() {
+            // these stay journaled as pending, so get retried next start
+            eprintln!("Couldn't commit the index: {}", e);
+            uncommitted.clear();
+            return;
+        }
+
+        for (path, update) in uncommitted.drain(..) {
+            if let Err(e) = modified_cache.commit(&path, update) {
+                eprintln!("Couldn't update the modified cache for {:?}: {}", path, e);
+            }
+        }
     }
 
     pub fn join(self) {
@@ -291,3 +1358,226 @@ impl IndexerThreads {
         self.index_sender.send(req).expect("Failed adding job");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::AnalyzerStep;
+    use crate::file_record::FileStat;
+    use crate::secrets::SecretProvider;
+    use tantivy::collector::{Count, TopDocs};
+    use tantivy::query::TermQuery;
+    use tantivy::IndexReader;
+
+    fn test_config(name: &str) -> config::Config {
+        let dir = std::env::temp_dir().join(format!("sids-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+
+        config::Config {
+            index_location: dir,
+            root_globs: Vec::new(),
+            indexed_exts: Vec::new(),
+            ignored_globs: Vec::new(),
+            history_files: Vec::new(),
+            man_paths: Vec::new(),
+            media_player: Vec::new(),
+            analyzer: vec![AnalyzerStep::Lowercase],
+            encrypted_policy: EncryptedPolicy::IndexOnly,
+            encrypted_roots: Vec::new(),
+            history_roots: Vec::new(),
+            secret_provider: SecretProvider::None,
+            indexing: config::IndexingConfig {
+                profile: config::ResourceProfile::LowResource,
+                workers: None,
+                writer_threads: None,
+                writer_heap_mb: None,
+                queue_depth: None,
+            },
+            commit: config::CommitConfig {
+                max_docs: 1000,
+                max_pending_mb: 100,
+                max_delay_secs: 30,
+                idle_secs: 2,
+            },
+            merge: config::MergeConfig {
+                policy: config::MergePolicyKind::Log,
+                min_merge_segments: 8,
+                min_layer_docs: 10_000,
+                level_log_size: 0.75,
+            },
+            collections: Vec::new(),
+            shared_indexes: Vec::new(),
+        }
+    }
+
+    /// an in memory index with a writer, and a reader to check it with
+    fn test_index(name: &str) -> (DocSchema, DocWriter, IndexReader) {
+        let config = test_config(name);
+        let schema = DocSchema::new();
+        let index = tantivy::Index::create_in_ram(schema.schema.clone());
+        analyzer::register(&index, &config.analyzer);
+
+        let writer = index.writer_with_num_threads(1, 10_000_000).unwrap();
+        let manual = || {
+            index
+                .reader_builder()
+                .reload_policy(tantivy::ReloadPolicy::Manual)
+                .try_into()
+                .unwrap()
+        };
+        let cache = LastModifiedCache::new(&config).unwrap();
+        let doc_writer = DocWriter::new(
+            writer,
+            manual(),
+            schema.clone(),
+            cache,
+            History::new(&config),
+        );
+
+        (schema, doc_writer, manual())
+    }
+
+    fn count(reader: &IndexReader, term: Term) -> usize {
+        reader.reload().unwrap();
+        let query = TermQuery::new(term, IndexRecordOption::Basic);
+        reader.searcher().search(&query, &Count).unwrap()
+    }
+
+    /// how many documents containing the word list the file, a file should
+    /// never be in more than one
+    fn listed(reader: &IndexReader, schema: &DocSchema, path: &Path, word: &str) -> usize {
+        reader.reload().unwrap();
+        let searcher = reader.searcher();
+        let term = Term::from_field_text(schema.content(), word);
+        let query = TermQuery::new(term, IndexRecordOption::Basic);
+
+        let found = searcher.search(&query, &Count).unwrap();
+        if found == 0 {
+            return 0;
+        }
+
+        let hits = searcher
+            .search(&query, &TopDocs::with_limit(found))
+            .unwrap();
+        hits.into_iter()
+            .map(|(_, address)| searcher.doc(address).unwrap())
+            .filter(|doc| {
+                doc.get_all(schema.full_path())
+                    .iter()
+                    .any(|v| v.text() == path.to_str())
+            })
+            .count()
+    }
+
+    fn write(schema: &DocSchema, hash: Option<ContentHash>, text: &str) -> WriteAction {
+        WriteAction::Write {
+            hash,
+            docs: vec![doc!(schema.content() => text)],
+        }
+    }
+
+    /// apply and commit an action the way the writer thread does, so the
+    /// modified cache knows each file's content hash afterwards
+    fn index(writer: &mut DocWriter, path: &Path, action: WriteAction) {
+        let indexed = |hash| CacheUpdate::Indexed {
+            stat: FileStat {
+                mtime_ns: 1,
+                size: 1,
+                dev: 1,
+                inode: 1,
+            },
+            hash,
+            extractor: FileKind::Text.extractor(),
+        };
+        let update = match &action {
+            WriteAction::Remove => CacheUpdate::Deleted,
+            WriteAction::Write { hash, .. } => indexed(*hash),
+            WriteAction::Share { hash } => indexed(Some(*hash)),
+        };
+
+        writer.apply(path, action).unwrap();
+        let cache = writer.modified_cache().clone();
+        IndexerThreads::commit(writer, &cache, &mut vec![(path.to_path_buf(), update)]);
+    }
+
+    #[test]
+    fn rewrite_and_delete_leave_nothing_behind() {
+        let (schema, mut writer, reader) = test_index("upsert");
+        let path = Path::new("/notes/a.txt");
+        let content = |word| Term::from_field_text(schema.content(), word);
+
+        writer.apply(path, write(&schema, None, "first")).unwrap();
+        writer.commit().unwrap();
+        assert_eq!(count(&reader, schema.doc_id_term(path)), 1);
+
+        writer.apply(path, write(&schema, None, "second")).unwrap();
+        writer.commit().unwrap();
+        assert_eq!(count(&reader, schema.doc_id_term(path)), 1);
+        assert_eq!(count(&reader, content("first")), 0);
+        assert_eq!(count(&reader, content("second")), 1);
+
+        writer.apply(path, WriteAction::Remove).unwrap();
+        writer.commit().unwrap();
+        assert_eq!(count(&reader, schema.doc_id_term(path)), 0);
+        assert_eq!(count(&reader, content("second")), 0);
+
+        let _ = fs::remove_dir_all(test_config("upsert").index_location);
+    }
+
+    #[test]
+    fn copies_are_each_listed_once() {
+        let (schema, mut writer, reader) = test_index("copies");
+        let (a, b) = (Path::new("/notes/a.txt"), Path::new("/backup/a.txt"));
+        let (first, second) = ([1; 32], [2; 32]);
+        let listed = |path, word| listed(&reader, &schema, path, word);
+        let content = |word| Term::from_field_text(schema.content(), word);
+
+        index(&mut writer, a, write(&schema, Some(first), "first"));
+        assert_eq!(listed(a, "first"), 1);
+
+        // a copy shares the document
+        index(&mut writer, b, WriteAction::Share { hash: first });
+        assert_eq!(listed(a, "first"), 1);
+        assert_eq!(listed(b, "first"), 1);
+        assert_eq!(count(&reader, content("first")), 1);
+
+        // re-indexing the same content changes nothing
+        index(&mut writer, a, write(&schema, Some(first), "first"));
+        assert_eq!(listed(a, "first"), 1);
+        assert_eq!(listed(b, "first"), 1);
+
+        // the modified file moves to its new content, the copy stays
+        index(&mut writer, a, write(&schema, Some(second), "second"));
+        assert_eq!(listed(a, "first"), 0);
+        assert_eq!(listed(a, "second"), 1);
+        assert_eq!(listed(b, "first"), 1);
+
+        index(&mut writer, a, WriteAction::Remove);
+        assert_eq!(listed(a, "second"), 0);
+        assert_eq!(listed(b, "first"), 1);
+
+        index(&mut writer, b, WriteAction::Remove);
+        assert_eq!(listed(b, "first"), 0);
+        assert_eq!(count(&reader, content("first")), 0);
+        assert_eq!(count(&reader, content("second")), 0);
+
+        let _ = fs::remove_dir_all(test_config("copies").index_location);
+    }
+
+    #[test]
+    fn recovered_files_leave_their_old_content() {
+        let (schema, mut writer, reader) = test_index("recovered");
+        let path = Path::new("/notes/a.txt");
+
+        index(&mut writer, path, write(&schema, Some([1; 32]), "first"));
+
+        // what startup does with files that were never committed
+        writer.modified_cache().mark_stale(path).unwrap();
+
+        index(&mut writer, path, write(&schema, Some([2; 32]), "second"));
+        assert_eq!(listed(&reader, &schema, path, "first"), 0);
+        assert_eq!(listed(&reader, &schema, path, "second"), 1);
+
+        let _ = fs::remove_dir_all(test_config("recovered").index_location);
+    }
+}
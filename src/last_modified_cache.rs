@@ -1,7 +1,13 @@
 use sled;
 use snafu::{ResultExt, Snafu};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
+use super::analyzer;
 use super::config;
+use super::file_record::{ContentHash, FileRecord, FileStat};
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -20,19 +26,49 @@ pub enum FileCacheAction {
     NotIndexed,
 }
 
+/// what to record about a file once its documents have been committed
+#[derive(Debug, Copy, Clone)]
+pub enum CacheUpdate {
+    Indexed {
+        stat: FileStat,
+        hash: Option<ContentHash>,
+        /// name and version of the extractor used
+        extractor: (&'static str, u32),
+    },
+    /// extraction failed, forget the file so it gets another go next time
+    Failed,
+    Deleted,
+}
+
+/// the committed state of every indexed file, which only changes once
+/// tantivy has committed. files that have been sent to the indexer but not
+/// committed yet are journaled in the `pending` tree, anything still in
+/// there on startup didn't make it into the index.
+/// the `inodes` tree maps the device and inode of each file to its path,
+/// so we can tell when one has been moved. the `contents` tree has which
+/// extractor and analyzer the documents of each content hash came from
+#[derive(Clone)]
 pub struct LastModifiedCache {
     db: sled::Db,
+    pending: Arc<sled::Tree>,
+    inodes: Arc<sled::Tree>,
+    contents: Arc<sled::Tree>,
+    /// what records get tagged with, from the configured analyzer
+    analyzer_version: u32,
 }
 
-fn read_ne_u64(inp: &[u8]) -> u64 {
-    use std::convert::TryInto;
+fn inode_key(dev: u64, inode: u64) -> Vec<u8> {
+    let mut key = dev.to_le_bytes().to_vec();
+    key.extend_from_slice(&inode.to_le_bytes());
+    key
+}
 
-    let (int_bytes, _) = inp.split_at(std::mem::size_of::<u64>());
-    u64::from_ne_bytes(
-        int_bytes
-            .try_into()
-            .expect("Didn't get >=8 bytes to read u64 from (db fuckup)."),
-    )
+/// the analyzer version, then the extractor version and id
+fn versions_value(extractor: (&str, u32), analyzer: u32) -> Vec<u8> {
+    let mut value = analyzer.to_le_bytes().to_vec();
+    value.extend_from_slice(&extractor.1.to_le_bytes());
+    value.extend_from_slice(extractor.0.as_bytes());
+    value
 }
 
 impl LastModifiedCache {
@@ -40,6 +76,7 @@ impl LastModifiedCache {
         std::fs::create_dir_all(&config.index_location).unwrap();
 
         let modified_cache = config.index_location.join("modified_cache");
+        let analyzer_version = analyzer::version(&config.analyzer);
 
         let config = sled::ConfigBuilder::default()
             .path(&modified_cache)
@@ -47,50 +84,302 @@ impl LastModifiedCache {
             .build();
 
         let db = sled::Db::start(config).context(SledError)?;
+        let pending = db.open_tree("pending").context(SledError)?;
+        let inodes = db.open_tree("inodes").context(SledError)?;
+        let contents = db.open_tree("contents").context(SledError)?;
+
+        let cache = LastModifiedCache {
+            db,
+            pending,
+            inodes,
+            contents,
+            analyzer_version,
+        };
+        cache.migrate()?;
+
+        Ok(cache)
+    }
+
+    /// rewrite records in the old format, bare native endian seconds, as
+    /// current ones. they're still compared to the second until re-indexed
+    fn migrate(&self) -> Result<()> {
+        let mut migrated = 0;
+
+        for entry in self.db.iter() {
+            let (key, value) = entry.context(SledError)?;
 
-        Ok(LastModifiedCache { db })
+            if value.len() != 8 {
+                continue;
+            }
+
+            if let Some(record) = FileRecord::decode(&value) {
+                self.db.set(key, record.encode()).context(SledError)?;
+                migrated += 1;
+            }
+        }
+
+        if migrated > 0 {
+            println!("Migrated {} modified cache records", migrated);
+        }
+
+        Ok(())
+    }
+
+    /// the committed record of a file
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<FileRecord> {
+        let raw = self.db.get(path.as_ref().to_str().unwrap()).ok()??;
+        FileRecord::decode(&raw)
     }
 
     pub fn len(&self) -> usize {
         self.db.len()
     }
 
-    /// remove a file from the indexed cache if it exists,
-    /// returns true if the file was indexed, false otherwise
-    pub fn remove_file<P: AsRef<std::path::Path>>(
-        &self,
-        path: P
-    ) -> bool {
-        self.db.del(path.as_ref().to_str().unwrap()).unwrap().is_some()
+    /// every file we have a record of
+    pub fn paths(&self) -> Result<Vec<PathBuf>> {
+        self.db
+            .iter()
+            .keys()
+            .map(|k| {
+                k.map(|k| PathBuf::from(String::from_utf8_lossy(&k).into_owned()))
+                    .context(SledError)
+            })
+            .collect()
+    }
+
+    /// forget every file, so they all get indexed again
+    pub fn clear(&self) -> Result<()> {
+        self.db.clear().context(SledError)?;
+        self.pending.clear().context(SledError)?;
+        self.inodes.clear().context(SledError)?;
+        self.contents.clear().context(SledError)
+    }
+
+    /// whether the indexed documents of some content came from the current
+    /// analyzer and the given extractor. content indexed before we kept
+    /// track of this doesn't count
+    pub fn is_current(&self, hash: &ContentHash, extractor: (&str, u32)) -> bool {
+        let current = versions_value(extractor, self.analyzer_version);
+
+        match self.contents.get(hash) {
+            Ok(Some(value)) => value[..] == current[..],
+            _ => false,
+        }
+    }
+
+    /// make a file's record match no stat, so the walk indexes it again
+    /// if its content changed. the hash is kept, the index may still list
+    /// the file under that content and the writer needs it to take it out
+    pub fn mark_stale<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let record = match self.get(&path) {
+            Some(record) => record,
+            None => return Ok(()),
+        };
+
+        let stale = FileRecord {
+            mtime_ns: u64::max_value(),
+            ..record
+        };
+        self.db
+            .set(path.as_ref().to_str().unwrap(), stale.encode())
+            .context(SledError)?;
+        Ok(())
+    }
+
+    fn mark_pending(&self, key: &str) -> Result<()> {
+        self.pending.set(key, &[] as &[u8]).context(SledError)?;
+        Ok(())
+    }
+
+    /// every file indexed by an older extractor or analyzer than the current
+    /// ones. `extractor_for` gives the extractor a file would use now, files
+    /// it gives None for aren't ours to re-index any more
+    pub fn stale_files<F>(&self, extractor_for: F) -> Result<Vec<PathBuf>>
+    where
+        F: Fn(&Path) -> Option<(&'static str, u32)>,
+    {
+        let mut stale = Vec::new();
+
+        for entry in self.db.iter() {
+            let (key, value) = entry.context(SledError)?;
+            let path = PathBuf::from(String::from_utf8_lossy(&key).into_owned());
+
+            let record = match FileRecord::decode(&value) {
+                Some(record) => record,
+                None => continue,
+            };
+
+            if let Some(extractor) = extractor_for(&path) {
+                if record.is_stale(extractor, self.analyzer_version) {
+                    stale.push(path);
+                }
+            }
+        }
+
+        Ok(stale)
     }
 
-    pub fn check_file<P: AsRef<std::path::Path>>(
+    /// check a file is still stale, if it is it's journaled for re-indexing
+    pub fn check_stale<P: AsRef<Path>>(
         &self,
         path: P,
-        modified: u64,
-    ) -> Result<FileCacheAction> {
-        Ok(
-            match self
-                .db
-                .set(path.as_ref().to_str().unwrap(), &modified.to_ne_bytes())
-                .context(SledError)?
-            {
-                Some(prev_modified_buf) => {
-                    let prev_modified = read_ne_u64(prev_modified_buf.as_ref());
-                    if modified != prev_modified {
-                        FileCacheAction::Outdated
-                    } else {
-                        FileCacheAction::UptoDate
+        extractor: (&'static str, u32),
+    ) -> Result<bool> {
+        let key = path.as_ref().to_str().unwrap();
+
+        let stale = match self.get(&path) {
+            Some(record) => record.is_stale(extractor, self.analyzer_version),
+            None => false,
+        };
+
+        if stale {
+            self.mark_pending(key)?;
+        }
+
+        Ok(stale)
+    }
+
+    /// check whether a deleted file was indexed, if it was its removal is
+    /// journaled until the delete is committed
+    pub fn remove_file<P: AsRef<Path>>(&self, path: P) -> bool {
+        let key = path.as_ref().to_str().unwrap();
+
+        let was_indexed = self.db.contains_key(key).unwrap_or(false);
+        if was_indexed {
+            let _ = self.mark_pending(key);
+        }
+
+        was_indexed
+    }
+
+    /// check a file against what's committed, files needing indexing are
+    /// journaled until they're committed
+    pub fn check_file<P: AsRef<Path>>(&self, path: P, stat: &FileStat) -> Result<FileCacheAction> {
+        let key = path.as_ref().to_str().unwrap();
+
+        let record = self.db.get(key).context(SledError)?;
+
+        // a record we can't read is as good as none
+        let action = match record.as_ref().map(|r| FileRecord::decode(r)) {
+            Some(Some(ref record)) if record.matches(stat) => FileCacheAction::UptoDate,
+            // touched or rewritten with the same content, nothing to re-index
+            Some(Some(ref record)) if record.same_content(&path, stat) => {
+                let touched = FileRecord {
+                    mtime_ns: stat.mtime_ns,
+                    inode: Some((stat.dev, stat.inode)),
+                    ..record.clone()
+                };
+                self.db.set(key, touched.encode()).context(SledError)?;
+
+                FileCacheAction::UptoDate
+            }
+            Some(Some(_)) => FileCacheAction::Outdated,
+            Some(None) | None => FileCacheAction::NotIndexed,
+        };
+
+        match action {
+            FileCacheAction::UptoDate => (),
+            _ => self.mark_pending(key)?,
+        }
+
+        Ok(action)
+    }
+
+    /// find the path a new file was moved from: one we've indexed with the
+    /// same inode and content, which isn't there any more
+    pub fn find_moved<P: AsRef<Path>>(&self, path: P, stat: &FileStat) -> Option<PathBuf> {
+        let from = self.inodes.get(inode_key(stat.dev, stat.inode)).ok()??;
+        let from = PathBuf::from(String::from_utf8_lossy(&from).into_owned());
+
+        if from == path.as_ref() || from.exists() {
+            return None;
+        }
+
+        let record = self.get(&from)?;
+
+        if record.inode == Some((stat.dev, stat.inode)) && record.same_content(&path, stat) {
+            Some(from)
+        } else {
+            None
+        }
+    }
+
+    /// record a file's new state, only call this once tantivy has committed
+    /// its documents
+    pub fn commit<P: AsRef<Path>>(&self, path: P, update: CacheUpdate) -> Result<()> {
+        let key = path.as_ref().to_str().unwrap();
+
+        match update {
+            CacheUpdate::Indexed {
+                stat,
+                hash,
+                extractor: (id, version),
+            } => {
+                let record = FileRecord {
+                    hash,
+                    extractor: Some((id.to_owned(), version)),
+                    analyzer: Some(self.analyzer_version),
+                    ..FileRecord::new(&stat)
+                };
+
+                self.db.set(key, record.encode()).context(SledError)?;
+                self.inodes
+                    .set(inode_key(stat.dev, stat.inode), key)
+                    .context(SledError)?;
+
+                if let Some(hash) = &hash {
+                    let versions = versions_value((id, version), self.analyzer_version);
+                    self.contents.set(hash, versions).context(SledError)?;
+                }
+            }
+            CacheUpdate::Failed | CacheUpdate::Deleted => {
+                let old = self.db.del(key).context(SledError)?;
+
+                // the inode may belong to another path by now
+                let old_inode = old.and_then(|r| FileRecord::decode(&r)?.inode);
+                if let Some((dev, inode)) = old_inode {
+                    let ikey = inode_key(dev, inode);
+                    if self
+                        .inodes
+                        .get(&ikey)
+                        .context(SledError)?
+                        .as_ref()
+                        .map(|p| &p[..])
+                        == Some(key.as_bytes())
+                    {
+                        self.inodes.del(&ikey).context(SledError)?;
                     }
                 }
-                None => FileCacheAction::NotIndexed,
-            },
-        )
+            }
+        }
+
+        self.pending.del(key).context(SledError)?;
+        Ok(())
+    }
+
+    /// take the files which were sent to the indexer but never committed,
+    /// clearing the journal
+    pub fn take_uncommitted(&self) -> Result<Vec<PathBuf>> {
+        let paths = self
+            .pending
+            .iter()
+            .keys()
+            .map(|k| {
+                k.map(|k| PathBuf::from(String::from_utf8_lossy(&k).into_owned()))
+                    .context(SledError)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        self.pending.clear().context(SledError)?;
+
+        Ok(paths)
     }
 }
 
 impl std::ops::Drop for LastModifiedCache {
     fn drop(&mut self) {
         let _ = self.db.flush();
+        let _ = self.pending.flush();
+        let _ = self.inodes.flush();
     }
 }
@@ -5,12 +5,24 @@ use std::sync::{
     Arc,
 };
 
+mod analyzer;
+mod commit_policy;
 mod config;
+mod doc_writer;
 mod file_collector;
+mod file_record;
+mod history;
+mod index_archive;
+mod index_calendar;
+mod index_history;
+mod index_man;
+mod index_pdf;
+mod index_subtitles;
 mod indexer;
 mod searcher;
 mod last_modified_cache;
-mod once_every;
+mod secrets;
+mod simhash;
 mod gui;
 
 #[derive(Debug, Snafu)]
@@ -34,10 +46,35 @@ struct IndexerData {
 
 fn deploy_indexer(mut data: IndexerData) -> std::thread::JoinHandle<()> {
     std::thread::spawn(move || {
-        for file in data.file_collector {
-            if let Ok(file) = file {
-                data.doc_indexer.add_job(indexer::IndexRequest(file));
-                data.indexed_files.fetch_add(1, Ordering::Relaxed);
+        use file_collector::CollectorEvent;
+
+        for event in data.file_collector {
+            match event {
+                Ok(CollectorEvent::File(file)) => {
+                    data.doc_indexer.add_job(indexer::IndexRequest(file));
+                    data.indexed_files.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(CollectorEvent::CrawlFinished { complete: false })
+                    if data.doc_indexer.migration().is_some() =>
+                {
+                    // the rebuilt index is missing whatever is in those, keep
+                    // searching the old one and rebuild again next time
+                    eprintln!("Not all roots could be walked, keeping the old index for now");
+                }
+                Ok(CollectorEvent::CrawlFinished { complete: true })
+                    if data.doc_indexer.migration().is_some() =>
+                {
+                    // everything's been re-indexed, swap the new index in
+                    let swapped = data
+                        .doc_indexer
+                        .flush()
+                        .and_then(|_| data.doc_indexer.finish_migration());
+
+                    if let Err(e) = swapped {
+                        eprintln!("Couldn't finish rebuilding the index: {}", e);
+                    }
+                }
+                _ => (),
             }
 
             if !data.running.load(Ordering::Relaxed) {
@@ -66,36 +103,72 @@ fn main_inner() -> Result<(), SIDSError> {
 
     println!("config: {:#?}", config);
 
-    let modified_cache =
-        last_modified_cache::LastModifiedCache::new(&config).context(LastModifiedCacheError)?;
+    let running = deploy_cc_handler();
 
-    let mut doc_indexer = indexer::DocIndexer::new(&config).context(IndexerError)?;
-    doc_indexer.spawn_workers().context(IndexerError)?;
+    let indexed_files = Arc::new(AtomicUsize::new(0));
+    let mut searchers = Vec::new();
+    let mut commit_handles = Vec::new();
+    let mut indexer_threads = Vec::new();
 
-    let indexer = doc_indexer.indexer().clone();
-    let schema = doc_indexer.schema().clone();
+    // each collection has its own index, cache and crawl
+    for (name, config) in config.collections() {
+        let modified_cache =
+            last_modified_cache::LastModifiedCache::new(&config).context(LastModifiedCacheError)?;
 
-    let searcher = searcher::Searcher::new(schema, indexer).unwrap();
+        let mut doc_indexer =
+            indexer::DocIndexer::new(&config, modified_cache.clone()).context(IndexerError)?;
+        doc_indexer.spawn_workers().context(IndexerError)?;
 
-    let running = deploy_cc_handler();
+        // the cache describes the old index, everything needs indexing again
+        if doc_indexer.migration().is_some() {
+            modified_cache.clear().context(LastModifiedCacheError)?;
+        }
 
-    let indexed_files = Arc::new(AtomicUsize::new(modified_cache.len()));
+        let indexer = doc_indexer.indexer().clone();
+        let schema = doc_indexer.schema().clone();
+        let migration = doc_indexer.migration().cloned();
+        commit_handles.push(doc_indexer.commit_handle());
 
-    let indexer_data = IndexerData {
-        file_collector: file_collector::collect_files(&config, modified_cache).context(CollectorError)?,
-        doc_indexer,
-        indexed_files: indexed_files.clone(),
-        running: running.clone(),
-    };
+        let searcher = searcher::Searcher::new(schema, indexer, migration).unwrap();
+        searchers.push((name, searcher));
 
-    let indexer_thread = deploy_indexer(indexer_data);
+        indexed_files.fetch_add(modified_cache.len(), Ordering::Relaxed);
 
-    gui::spawn(searcher, indexed_files);
+        let indexer_data = IndexerData {
+            file_collector: file_collector::collect_files(&config, modified_cache).context(CollectorError)?,
+            doc_indexer,
+            indexed_files: indexed_files.clone(),
+            running: running.clone(),
+        };
+
+        indexer_threads.push(deploy_indexer(indexer_data));
+    }
+
+    // someone else keeps these up to date, we only search them
+    for shared in &config.shared_indexes {
+        let opened = indexer::open_shared(&shared.index_location, &config);
+
+        match opened {
+            Ok((schema, index)) => {
+                match searcher::Searcher::shared(schema, index, shared.path_prefixes.clone()) {
+                    Some(searcher) => searchers.push((shared.name.clone(), searcher)),
+                    None => eprintln!("Couldn't read shared index {}", shared.name),
+                }
+            }
+            Err(e) => eprintln!("Couldn't open shared index {}: {}", shared.name, e),
+        }
+    }
+
+    let collections = searcher::Collections::new(searchers);
+
+    gui::spawn(collections, indexed_files, commit_handles, config.media_player.clone());
 
     // set running to false when the gui quits
     running.store(false, Ordering::Relaxed);
 
-    let _ = indexer_thread.join();
+    for indexer_thread in indexer_threads {
+        let _ = indexer_thread.join();
+    }
 
     Ok(())
 }
@@ -1,47 +1,808 @@
-use tantivy::{collector::TopDocs, query::QueryParser, DocAddress, Index, IndexReader, Score, SnippetGenerator};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tantivy::{
+    collector::{FacetCollector, TopDocs},
+    query::{BooleanQuery, Occur, Query, QueryParser, RangeQuery, RegexQuery, TermQuery},
+    schema::{Facet, Field, IndexRecordOption},
+    DocAddress, Index, IndexReader, ReloadPolicy, Score, SnippetGenerator, Term,
+};
+
+use super::config::PathPrefix;
+use super::indexer::{DocSchema, Migration, SegmentStats};
+use super::simhash;
+
+/// how many results a search gives
+const RESULTS: usize = 10;
+/// collapsing versions and finding similar ones throw hits away, so this
+/// many more are fetched to start with
+const CANDIDATES_PER_RESULT: usize = 5;
 
 pub struct SearchResult {
     pub path: String,
+    /// other files with the same content
+    pub other_paths: Vec<String>,
+    /// the simhash of the content, if there was enough to fingerprint
+    pub fingerprint: Option<u64>,
+    /// paths of other versions of the content, when they've been collapsed
+    /// into this result
+    pub variants: Vec<String>,
     pub snippet: String,
+    /// where in the media a subtitle cue starts, in seconds
+    pub media_offset: Option<u64>,
+    /// summary of an event, name of a contact or author of a message
+    pub title: Option<String>,
+    /// when the record happened, seconds since the epoch
+    pub timestamp: Option<u64>,
+    /// set when the hit was in a past version of the file
+    pub past_version: Option<PastVersion>,
+    /// which collection the result is from, when there's more than one
+    pub collection: Option<String>,
+    /// what results are ranked by: how well they matched, or the mtime or
+    /// size when sorting by those
+    pub score: f64,
+}
+
+/// when a past version of a file was written and when it was replaced,
+/// seconds since the epoch
+pub struct PastVersion {
+    pub modified: Option<u64>,
+    pub replaced: u64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SortBy {
+    Relevance,
+    /// most recently modified first
+    Modified,
+    /// largest first
+    Size,
+}
+
+/// restrictions pulled out of the query text, such as
+/// `ext:pdf owner:ben dir:/home/ben/papers sort:modified`.
+/// `collapse:on` folds versions of the same text into one result and
+/// `similar:<fingerprint>` finds versions of some text. `version:any` also
+/// searches the past versions kept for history roots
+#[derive(Debug)]
+struct Filters {
+    sort: SortBy,
+    extension: Option<String>,
+    owner: Option<String>,
+    directory: Option<PathBuf>,
+    collapse: bool,
+    similar: Option<u64>,
+    any_version: bool,
+}
+
+impl Filters {
+    /// split the filters out of a query, returning the rest for the query parser
+    fn parse(search: &str) -> (String, Filters) {
+        let mut filters = Filters {
+            sort: SortBy::Relevance,
+            extension: None,
+            owner: None,
+            directory: None,
+            collapse: false,
+            similar: None,
+            any_version: false,
+        };
+        let mut rest = Vec::new();
+
+        for word in search.split_whitespace() {
+            let mut split = word.splitn(2, ':');
+
+            match (split.next().unwrap_or(""), split.next().unwrap_or("")) {
+                ("ext", ext) if !ext.is_empty() => {
+                    filters.extension = Some(ext.trim_start_matches('.').to_lowercase())
+                }
+                ("owner", owner) if !owner.is_empty() => filters.owner = Some(owner.to_owned()),
+                ("dir", dir) if !dir.is_empty() => filters.directory = Some(PathBuf::from(dir)),
+                ("sort", "modified") => filters.sort = SortBy::Modified,
+                ("sort", "size") => filters.sort = SortBy::Size,
+                ("sort", "relevance") => filters.sort = SortBy::Relevance,
+                ("collapse", "on") => filters.collapse = true,
+                ("collapse", "off") => filters.collapse = false,
+                ("similar", fp) if u64::from_str_radix(fp, 16).is_ok() => {
+                    filters.similar = u64::from_str_radix(fp, 16).ok()
+                }
+                ("version", "any") => filters.any_version = true,
+                ("version", "current") => filters.any_version = false,
+                _ => rest.push(word),
+            }
+        }
+
+        (rest.join(" "), filters)
+    }
+
+    fn terms(&self, schema: &DocSchema) -> Vec<Term> {
+        let mut terms = Vec::new();
+
+        if let Some(ext) = &self.extension {
+            terms.push(Term::from_facet(
+                schema.extension(),
+                &Facet::from_path(vec![ext]),
+            ));
+        }
+
+        if let Some(owner) = &self.owner {
+            terms.push(Term::from_facet(
+                schema.owner(),
+                &Facet::from_path(vec![owner]),
+            ));
+        }
+
+        // the facet is indexed with its ancestors, so this matches the
+        // whole subtree under the directory
+        if let Some(dir) = &self.directory {
+            terms.push(Term::from_facet(
+                schema.directory(),
+                &DocSchema::directory_facet(dir),
+            ));
+        }
+
+        terms
+    }
+}
+
+/// an index with an older schema, searched while the current one is being
+/// rebuilt. all we can rely on it having are the content and path fields
+struct LegacyIndex {
+    migration: Arc<Migration>,
+    index_reader: IndexReader,
+    content: Field,
+    full_path: Field,
+}
+
+impl LegacyIndex {
+    fn new(migration: Arc<Migration>) -> Option<Self> {
+        let index = migration.old_index();
+        let schema = index.schema();
+
+        Some(LegacyIndex {
+            index_reader: index.reader().ok()?,
+            content: schema.get_field("content")?,
+            full_path: schema.get_field("full_path")?,
+            migration,
+        })
+    }
+
+    fn search(&self, search: &str) -> Option<Vec<SearchResult>> {
+        let searcher = self.index_reader.searcher();
+        let (search, _) = Filters::parse(search);
+
+        let qp = QueryParser::for_index(self.migration.old_index(), vec![self.content]);
+        let q = qp.parse_query(&search).ok()?;
+
+        let top_docs: Vec<(Score, DocAddress)> =
+            searcher.search(&q, &TopDocs::with_limit(10)).ok()?;
+
+        let mut snippet_generator = SnippetGenerator::create(&searcher, &*q, self.content).ok()?;
+        snippet_generator.set_max_num_chars(100);
+
+        top_docs
+            .into_iter()
+            .map(|(score, addr)| {
+                let doc = searcher.doc(addr).ok()?;
+                Some(SearchResult {
+                    path: doc.get_first(self.full_path)?.text()?.to_owned(),
+                    other_paths: Vec::new(),
+                    fingerprint: None,
+                    variants: Vec::new(),
+                    snippet: snippet_generator.snippet_from_doc(&doc).to_html(),
+                    media_offset: None,
+                    title: None,
+                    timestamp: None,
+                    past_version: None,
+                    collection: None,
+                    score: f64::from(score),
+                })
+            })
+            .collect()
+    }
+}
+
+/// partial words shorter than this aren't expanded, they'd match most of the index
+const MIN_PREFIX_CHARS: usize = 2;
+
+/// the word still being typed at the end of a query, if there is one
+fn partial_word(search: &str) -> Option<&str> {
+    if search.ends_with(char::is_whitespace) {
+        return None;
+    }
+
+    let word = search.split_whitespace().last()?;
+
+    // filters, field queries, phrases and exclusions are left as they are
+    if word.contains(|c| c == ':' || c == '"') || word.starts_with('-') {
+        return None;
+    }
+
+    Some(word)
+}
+
+fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        if "\\.+*?()|[]{}^$#&-~".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// fold results that are versions of a better ranked result into its variants
+fn collapse(results: Vec<SearchResult>) -> Vec<SearchResult> {
+    let mut kept: Vec<SearchResult> = Vec::new();
+
+    for result in results {
+        let version_of = result.fingerprint.and_then(|fingerprint| {
+            kept.iter_mut().find(|k| {
+                k.fingerprint.map_or(false, |f| {
+                    simhash::distance(f, fingerprint) <= simhash::MAX_DISTANCE
+                })
+            })
+        });
+
+        match version_of {
+            // another record of the same file isn't another version
+            Some(k) => {
+                for path in std::iter::once(result.path).chain(result.variants) {
+                    if k.path != path && !k.variants.contains(&path) {
+                        k.variants.push(path);
+                    }
+                }
+            }
+            None => kept.push(result),
+        }
+    }
+
+    kept
 }
 
 pub struct Searcher {
-    schema: super::indexer::DocSchema,
+    schema: DocSchema,
     index: Index,
     index_reader: IndexReader,
+    legacy: Option<LegacyIndex>,
+    /// where paths in the index are on this machine
+    prefixes: Vec<PathPrefix>,
+    /// whether to reload before searching, for indexes we can't watch
+    reload: bool,
 }
 
 impl Searcher {
-    pub fn new(schema: super::indexer::DocSchema, index: Index) -> Option<Self> {
+    pub fn new(schema: DocSchema, index: Index, migration: Option<Arc<Migration>>) -> Option<Self> {
         let index_reader = index.reader().ok()?;
 
         Some(Searcher {
             schema,
             index,
             index_reader,
+            legacy: migration.and_then(LegacyIndex::new),
+            prefixes: Vec::new(),
+            reload: false,
+        })
+    }
+
+    /// search an index someone else writes. not every network filesystem
+    /// lets us watch for their commits, then we check before each search
+    pub fn shared(schema: DocSchema, index: Index, prefixes: Vec<PathPrefix>) -> Option<Self> {
+        let (index_reader, reload) = match index.reader() {
+            Ok(reader) => (reader, false),
+            Err(_) => {
+                let reader = index
+                    .reader_builder()
+                    .reload_policy(ReloadPolicy::Manual)
+                    .try_into()
+                    .ok()?;
+                (reader, true)
+            }
+        };
+
+        Some(Searcher {
+            schema,
+            index,
+            index_reader,
+            legacy: None,
+            prefixes,
+            reload,
         })
     }
 
+    fn refresh(&self) {
+        if self.reload {
+            if let Err(e) = self.index_reader.reload() {
+                eprintln!("Couldn't reload shared index: {}", e);
+            }
+        }
+    }
+
+    /// where a path from the index is on this machine
+    fn local_path(&self, path: &str) -> String {
+        for prefix in &self.prefixes {
+            if let Ok(rest) = Path::new(path).strip_prefix(&prefix.from) {
+                return prefix.to.join(rest).to_string_lossy().into_owned();
+            }
+        }
+
+        path.to_owned()
+    }
+
+    /// where a local path is in the index, the reverse of `local_path`
+    fn indexed_path(&self, path: &Path) -> PathBuf {
+        for prefix in &self.prefixes {
+            if let Ok(rest) = path.strip_prefix(&prefix.to) {
+                return prefix.from.join(rest);
+            }
+        }
+
+        path.to_path_buf()
+    }
+
+    /// the old index, while the current one is still being rebuilt
+    fn legacy(&self) -> Option<&LegacyIndex> {
+        self.legacy.as_ref().filter(|l| !l.migration.is_done())
+    }
+
+    /// parse a query, with its filters turned into required facet terms
+    fn parse_query(&self, search: &str) -> Option<(Box<dyn Query>, Filters)> {
+        let partial = partial_word(search);
+        let (search, mut filters) = Filters::parse(search);
+
+        // `dir:` is given as a local path
+        filters.directory = filters.directory.map(|d| self.indexed_path(&d));
+
+        let mut required: Vec<Box<dyn Query>> = filters
+            .terms(&self.schema)
+            .into_iter()
+            .map(|term| Box::new(TermQuery::new(term, IndexRecordOption::Basic)) as Box<dyn Query>)
+            .collect();
+
+        // anything sharing a band with the fingerprint, the search weeds
+        // out the ones that aren't close enough
+        if let Some(fingerprint) = filters.similar {
+            let bands: Vec<(Occur, Box<dyn Query>)> = simhash::bands(fingerprint)
+                .into_iter()
+                .map(|band| {
+                    let term = Term::from_field_text(self.schema.simhash_band(), &band);
+                    let query: Box<dyn Query> =
+                        Box::new(TermQuery::new(term, IndexRecordOption::Basic));
+                    (Occur::Should, query)
+                })
+                .collect();
+            required.push(Box::new(BooleanQuery::from(bands)));
+        }
+
+        // only past versions have when they were superseded
+        let mut excluded: Vec<Box<dyn Query>> = Vec::new();
+        if !filters.any_version {
+            excluded.push(Box::new(RangeQuery::new_u64(
+                self.schema.superseded_at(),
+                0..u64::max_value(),
+            )));
+        }
+
+        // filters on their own match everything they allow
+        if search.trim().is_empty() && !required.is_empty() {
+            let mut clauses: Vec<(Occur, Box<dyn Query>)> =
+                required.into_iter().map(|q| (Occur::Must, q)).collect();
+            clauses.extend(excluded.into_iter().map(|q| (Occur::MustNot, q)));
+            return Some((Box::new(BooleanQuery::from(clauses)), filters));
+        }
+
+        let normalized_fields = vec![
+            self.schema.content(),
+            self.schema.private_content(),
+            self.schema.filename(),
+        ];
+        let mut fields = normalized_fields.clone();
+        fields.extend(self.schema.stemmed_content());
+
+        let qp = QueryParser::for_index(&self.index, fields);
+        let mut q = qp.parse_query(&search).ok()?;
+
+        // anything matching the words as typed scores more than the
+        // constant score of a prefix match, so completed words rank higher
+        if let Some(partial) = partial {
+            let prefixes = self.prefix_queries(partial, &normalized_fields);
+
+            if !prefixes.is_empty() {
+                let mut clauses: Vec<(Occur, Box<dyn Query>)> = vec![(Occur::Should, q)];
+                clauses.extend(prefixes.into_iter().map(|p| (Occur::Should, p)));
+                q = Box::new(BooleanQuery::from(clauses));
+            }
+        }
+
+        if required.is_empty() && excluded.is_empty() {
+            return Some((q, filters));
+        }
+
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = vec![(Occur::Must, q)];
+        clauses.extend(required.into_iter().map(|q| (Occur::Must, q)));
+        clauses.extend(excluded.into_iter().map(|q| (Occur::MustNot, q)));
+
+        Some((Box::new(BooleanQuery::from(clauses)), filters))
+    }
+
+    /// match terms starting with a word that's still being typed, the word
+    /// goes through the same analyzer as the fields so it normalizes the same
+    fn prefix_queries(&self, word: &str, fields: &[Field]) -> Vec<Box<dyn Query>> {
+        let tokenizer = match self.index.tokenizer_for_field(self.schema.content()) {
+            Ok(t) => t,
+            Err(_) => return Vec::new(),
+        };
+
+        // `foo-ba` tokenizes into two words, only the last one is partial
+        let mut prefix = String::new();
+        tokenizer
+            .token_stream(word)
+            .process(&mut |token| prefix = token.text.clone());
+
+        if prefix.chars().count() < MIN_PREFIX_CHARS {
+            return Vec::new();
+        }
+
+        let pattern = format!("{}.*", escape_regex(&prefix));
+
+        fields
+            .iter()
+            .map(|&field| Box::new(RegexQuery::new(pattern.clone(), field)) as Box<dyn Query>)
+            .collect()
+    }
+
+    /// how common the terms of a search are in this index, None if it
+    /// can't be searched or is being rebuilt
+    fn term_stats(&self, search: &str) -> Option<TermStats> {
+        if self.legacy().is_some() {
+            return None;
+        }
+
+        self.refresh();
+        let (q, _) = self.parse_query(search)?;
+
+        Some(TermStats::of(&self.index_reader.searcher(), &*q))
+    }
+
+    /// whether results come from an old index while a new one is built
+    pub fn is_rebuilding(&self) -> bool {
+        self.legacy().is_some()
+    }
+
     pub fn search(&self, search: &str) -> Option<Vec<SearchResult>> {
+        if let Some(legacy) = self.legacy() {
+            return legacy.search(search);
+        }
+
+        self.refresh();
         let searcher = self.index_reader.searcher();
-        let qp = QueryParser::for_index(&self.index, vec![self.schema.content()]);
-        let q = qp.parse_query(search).ok()?;
+        let (q, filters) = self.parse_query(search)?;
+        let sort = filters.sort;
 
-        let top_docs: Vec<(Score, DocAddress)> =
-            searcher.search(&q, &TopDocs::with_limit(10)).ok()?;
+        let limit = if filters.collapse || filters.similar.is_some() {
+            RESULTS * CANDIDATES_PER_RESULT
+        } else {
+            RESULTS
+        };
+
+        let top_docs: Vec<(f64, DocAddress)> = match sort {
+            SortBy::Relevance => {
+                let top_docs: Vec<(Score, DocAddress)> =
+                    searcher.search(&*q, &TopDocs::with_limit(limit)).ok()?;
+                top_docs
+                    .into_iter()
+                    .map(|(score, addr)| (f64::from(score), addr))
+                    .collect()
+            }
+            SortBy::Modified | SortBy::Size => {
+                let field = if sort == SortBy::Modified {
+                    self.schema.mtime()
+                } else {
+                    self.schema.size()
+                };
+                let top_docs: Vec<(u64, DocAddress)> = searcher
+                    .search(&*q, &TopDocs::with_limit(limit).order_by_u64_field(field))
+                    .ok()?;
+                top_docs
+                    .into_iter()
+                    .map(|(value, addr)| (value as f64, addr))
+                    .collect()
+            }
+        };
 
         let mut snippet_generator = SnippetGenerator::create(&searcher, &*q, self.schema.content()).ok()?;
         snippet_generator.set_max_num_chars(100);
 
-        top_docs
+        let mut results = top_docs
             .into_iter()
-            .map(|(_, addr)| {
+            .map(|(score, addr)| {
                 let doc = searcher.doc(addr).ok()?;
                 let snippet = snippet_generator.snippet_from_doc(&doc);
                 let snippet_html = snippet.to_html();
-                let path = doc.get_first(self.schema.full_path())?.text()?.to_owned();
-                Some(SearchResult { path, snippet: snippet_html })
+                // copies of a file share a document, which lists all their paths
+                let mut paths = doc
+                    .get_all(self.schema.full_path())
+                    .into_iter()
+                    .filter_map(|v| v.text())
+                    .map(|p| self.local_path(p));
+                let path = paths.next()?;
+                let other_paths = paths.collect();
+                let media_offset = doc
+                    .get_first(self.schema.media_offset())
+                    .map(|v| v.u64_value());
+                let text_of = |field| doc.get_first(field).and_then(|v| v.text());
+                let title = match (
+                    text_of(self.schema.author()),
+                    text_of(self.schema.channel()),
+                ) {
+                    (Some(author), Some(channel)) => Some(format!("{} in {}", author, channel)),
+                    (Some(author), None) => Some(author.to_owned()),
+                    (None, Some(channel)) => Some(channel.to_owned()),
+                    (None, None) => text_of(self.schema.entry())
+                        .or_else(|| text_of(self.schema.summary()))
+                        .or_else(|| text_of(self.schema.name()))
+                        .map(str::to_owned),
+                };
+                let timestamp = doc
+                    .get_first(self.schema.timestamp())
+                    .map(|v| v.u64_value());
+                let fingerprint = doc.get_first(self.schema.simhash()).map(|v| v.u64_value());
+                let past_version =
+                    doc.get_first(self.schema.superseded_at())
+                        .map(|v| PastVersion {
+                            modified: doc.get_first(self.schema.mtime()).map(|v| v.u64_value()),
+                            replaced: v.u64_value(),
+                        });
+                Some(SearchResult {
+                    path,
+                    other_paths,
+                    fingerprint,
+                    variants: Vec::new(),
+                    snippet: snippet_html,
+                    media_offset,
+                    title,
+                    timestamp,
+                    past_version,
+                    collection: None,
+                    score,
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        if let Some(similar) = filters.similar {
+            let distance = |r: &SearchResult| r.fingerprint.map(|f| simhash::distance(f, similar));
+
+            results.retain(|r| distance(r).map_or(false, |d| d <= simhash::MAX_DISTANCE));
+
+            if sort == SortBy::Relevance {
+                results.sort_by_key(distance);
+            }
+        }
+
+        if filters.collapse {
+            results = collapse(results);
+        }
+
+        results.truncate(RESULTS);
+        Some(results)
+    }
+
+    /// how fragmented the index is, the old one's while rebuilding
+    pub fn segment_stats(&self) -> SegmentStats {
+        match self.legacy() {
+            Some(legacy) => SegmentStats::of(&legacy.index_reader.searcher()),
+            None => SegmentStats::of(&self.index_reader.searcher()),
+        }
+    }
+
+    /// how many matches of a query there are per file extension, most common first
+    pub fn extension_counts(&self, search: &str) -> Option<Vec<(String, u64)>> {
+        // older indexes may not have the extension facet
+        if self.legacy().is_some() {
+            return None;
+        }
+
+        self.refresh();
+        let searcher = self.index_reader.searcher();
+        let (q, _) = self.parse_query(search)?;
+
+        let mut collector = FacetCollector::for_field(self.schema.extension());
+        collector.add_facet("/");
+        let counts = searcher.search(&*q, &collector).ok()?;
+
+        Some(
+            counts
+                .top_k("/", 5)
+                .into_iter()
+                .map(|(facet, count)| (facet.to_path().join("/"), count))
+                .collect(),
+        )
+    }
+}
+
+/// how common the terms of a search are in one or more indexes
+#[derive(Default)]
+struct TermStats {
+    /// documents including deleted ones, as bm25 counts them
+    docs: u64,
+    /// how many documents each term is in
+    doc_freqs: BTreeMap<Term, u64>,
+}
+
+impl TermStats {
+    fn of(searcher: &tantivy::Searcher, query: &dyn Query) -> TermStats {
+        let mut terms = BTreeSet::new();
+        query.query_terms(&mut terms);
+
+        TermStats {
+            docs: searcher
+                .segment_readers()
+                .iter()
+                .map(|r| u64::from(r.max_doc()))
+                .sum(),
+            doc_freqs: terms
+                .into_iter()
+                .map(|t| {
+                    let freq = searcher.doc_freq(&t);
+                    (t, freq)
+                })
+                .collect(),
+        }
+    }
+
+    fn add(mut self, other: &TermStats) -> TermStats {
+        self.docs += other.docs;
+        for (term, freq) in &other.doc_freqs {
+            *self.doc_freqs.entry(term.clone()).or_insert(0) += freq;
+        }
+        self
+    }
+
+    /// the idf bm25 gives the terms, summed
+    fn idf(&self) -> f64 {
+        let n = self.docs as f64;
+
+        self.doc_freqs
+            .values()
+            .map(|&freq| {
+                let freq = freq as f64;
+                (1.0 + (n - freq + 0.5) / (freq + 0.5)).ln()
             })
+            .sum()
+    }
+}
+
+/// the searcher of each collection, searches can go to any of them
+pub struct Collections {
+    searchers: Vec<(String, Searcher)>,
+}
+
+impl Collections {
+    pub fn new(searchers: Vec<(String, Searcher)>) -> Collections {
+        Collections { searchers }
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.searchers
+            .iter()
+            .map(|(name, _)| name.clone())
             .collect()
     }
+
+    fn selected<'a>(&'a self, names: &'a [String]) -> impl Iterator<Item = &'a (String, Searcher)> {
+        self.searchers
+            .iter()
+            .filter(move |(name, _)| names.contains(name))
+    }
+
+    /// whether any of the collections is being rebuilt
+    pub fn is_rebuilding(&self, names: &[String]) -> bool {
+        self.selected(names).any(|(_, s)| s.is_rebuilding())
+    }
+
+    /// the segments of all the selected collections together
+    pub fn segment_stats(&self, names: &[String]) -> SegmentStats {
+        self.selected(names)
+            .map(|(_, s)| s.segment_stats())
+            .fold(SegmentStats::default(), SegmentStats::add)
+    }
+
+    /// search some of the collections. each index scores relevance by how
+    /// common the words are in it alone, so scores are scaled by how much
+    /// more or less common they are across all the searched indexes. this
+    /// is what one index holding everything would score for single words,
+    /// and close to it for several
+    pub fn search(&self, names: &[String], search: &str) -> Option<Vec<SearchResult>> {
+        let (_, filters) = Filters::parse(search);
+        let labelled = self.searchers.len() > 1;
+        let mut results = Vec::new();
+        let mut searched = false;
+        let mut failed = Vec::new();
+
+        let stats: Vec<Option<TermStats>> = self
+            .selected(names)
+            .map(|(_, s)| s.term_stats(search))
+            .collect();
+        let total = stats
+            .iter()
+            .flatten()
+            .fold(TermStats::default(), TermStats::add);
+
+        for ((name, searcher), stats) in self.selected(names).zip(&stats) {
+            let mut found = match searcher.search(search) {
+                Some(found) => found,
+                None => {
+                    failed.push(name);
+                    continue;
+                }
+            };
+            searched = true;
+
+            let scale = match stats {
+                Some(stats) if stats.idf() > 0.0 => total.idf() / stats.idf(),
+                _ => 1.0,
+            };
+
+            for result in &mut found {
+                if filters.sort == SortBy::Relevance {
+                    result.score *= scale;
+                }
+
+                if labelled {
+                    result.collection = Some(name.clone());
+                }
+            }
+
+            results.extend(found);
+        }
+
+        // if none of them could run it the query is likely just half typed,
+        // otherwise one broken index shouldn't hide what the others found
+        if !searched && !failed.is_empty() {
+            return None;
+        }
+        for name in failed {
+            eprintln!("Couldn't search {}, leaving it out", name);
+        }
+
+        match filters.similar {
+            // closest versions first, like searching a single index
+            Some(similar) if filters.sort == SortBy::Relevance => {
+                results.sort_by_key(|r| r.fingerprint.map(|f| simhash::distance(f, similar)))
+            }
+            _ => results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal)),
+        }
+
+        // versions can be in different collections
+        if filters.collapse {
+            results = collapse(results);
+        }
+
+        results.truncate(RESULTS);
+        Some(results)
+    }
+
+    /// how many matches there are per file extension across some of the
+    /// collections, most common first
+    pub fn extension_counts(&self, names: &[String], search: &str) -> Option<Vec<(String, u64)>> {
+        let mut totals: HashMap<String, u64> = HashMap::new();
+
+        for (_, searcher) in self.selected(names) {
+            for (ext, count) in searcher.extension_counts(search).unwrap_or_default() {
+                *totals.entry(ext).or_insert(0) += count;
+            }
+        }
+
+        let mut counts: Vec<(String, u64)> = totals.into_iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts.truncate(5);
+
+        Some(counts)
+    }
 }
@@ -0,0 +1,125 @@
+// looking up passwords for encrypted documents, and deciding what we're
+// allowed to keep from them
+
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use super::config;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SecretProvider {
+    None,
+    /// a file holding one password per line, each is tried in turn
+    File {
+        path: PathBuf,
+    },
+    /// a command printing passwords one per line,
+    /// `{file}` is substituted with the path of the encrypted file
+    Command {
+        command: Vec<String>,
+    },
+}
+
+/// what may end up in the index for a file we had to decrypt
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EncryptedPolicy {
+    /// don't index encrypted files at all
+    Skip,
+    /// make the content searchable, but don't store the plaintext
+    IndexOnly,
+    /// index and store the content like any other file
+    Store,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EncryptedRoot {
+    pub root: PathBuf,
+    pub policy: EncryptedPolicy,
+}
+
+/// text pulled out of a file, and whether we needed a password to get it
+pub struct Extracted {
+    pub text: String,
+    pub encrypted: bool,
+}
+
+impl Extracted {
+    pub fn plain(text: String) -> Extracted {
+        Extracted {
+            text,
+            encrypted: false,
+        }
+    }
+}
+
+pub struct Secrets {
+    provider: SecretProvider,
+    roots: Vec<EncryptedRoot>,
+    default_policy: EncryptedPolicy,
+}
+
+impl Secrets {
+    pub fn new(config: &config::Config) -> Secrets {
+        let mut roots = config.encrypted_roots.clone();
+
+        // most specific root first
+        roots.sort_by_key(|r| std::cmp::Reverse(r.root.components().count()));
+
+        Secrets {
+            provider: config.secret_provider.clone(),
+            roots,
+            default_policy: config.encrypted_policy,
+        }
+    }
+
+    /// the passwords to try for an encrypted file, the empty password is
+    /// always tried first since plenty of pdfs only have an owner password
+    pub fn passwords_for<P: AsRef<Path>>(&self, path: P) -> Vec<String> {
+        let mut passwords = vec![String::new()];
+
+        let output = match &self.provider {
+            SecretProvider::None => return passwords,
+            SecretProvider::File { path } => match fs::read_to_string(path) {
+                Ok(s) => s,
+                Err(e) => {
+                    eprintln!("Couldn't read password file {}: {}", path.display(), e);
+                    return passwords;
+                }
+            },
+            SecretProvider::Command { command } => {
+                let file = path.as_ref().to_str().unwrap();
+                let args: Vec<String> = command.iter().map(|a| a.replace("{file}", file)).collect();
+
+                let (cmd, args) = match args.split_first() {
+                    Some(split) => split,
+                    None => return passwords,
+                };
+
+                match Command::new(cmd).args(args).output() {
+                    Ok(o) => String::from_utf8_lossy(&o.stdout).into_owned(),
+                    Err(e) => {
+                        eprintln!("Couldn't run password command {}: {}", cmd, e);
+                        return passwords;
+                    }
+                }
+            }
+        };
+
+        passwords.extend(output.lines().filter(|l| !l.is_empty()).map(str::to_owned));
+        passwords
+    }
+
+    pub fn policy_for<P: AsRef<Path>>(&self, path: P) -> EncryptedPolicy {
+        self.roots
+            .iter()
+            .find(|r| path.as_ref().starts_with(&r.root))
+            .map(|r| r.policy)
+            .unwrap_or(self.default_policy)
+    }
+}
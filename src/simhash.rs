@@ -0,0 +1,139 @@
+// fingerprints of text that are close together when the texts are, for
+// finding edited versions of the same document
+
+/// how many words each shingle has
+const SHINGLE_WORDS: usize = 3;
+
+/// text with fewer words than this is too short to fingerprint usefully
+const MIN_WORDS: usize = 20;
+
+/// fingerprints this many bits apart or fewer are versions of the same text
+pub const MAX_DISTANCE: u32 = 3;
+
+/// fingerprints are indexed in this many bands. two within `MAX_DISTANCE`
+/// of each other always have one band the same, so there have to be more
+/// bands than that
+const BANDS: u32 = 4;
+
+/// the simhash of the 3 word shingles of some text, None if it's too short
+pub fn fingerprint(text: &str) -> Option<u64> {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+
+    if words.len() < MIN_WORDS {
+        return None;
+    }
+
+    let mut weights = [0i64; 64];
+
+    for shingle in words.windows(SHINGLE_WORDS) {
+        let hash = fnv1a(shingle);
+
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            if hash >> bit & 1 == 1 {
+                *weight += 1;
+            } else {
+                *weight -= 1;
+            }
+        }
+    }
+
+    Some(
+        weights
+            .iter()
+            .enumerate()
+            .filter(|(_, w)| **w > 0)
+            .fold(0u64, |fp, (bit, _)| fp | 1 << bit),
+    )
+}
+
+/// how many bits two fingerprints differ by
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// the terms a fingerprint is indexed under, `<band>:<bits>`
+pub fn bands(fingerprint: u64) -> Vec<String> {
+    let width = 64 / BANDS;
+
+    (0..BANDS)
+        .map(|band| {
+            let bits = fingerprint >> (band * width) & ((1 << width) - 1);
+            format!("{}:{:x}", band, bits)
+        })
+        .collect()
+}
+
+/// this needs to be the same everywhere and forever, so no `DefaultHasher`
+fn fnv1a(words: &[String]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+
+    for word in words {
+        for byte in word.bytes().chain(std::iter::once(0)) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "the index keeps one document per file and replaces it whenever \
+        the file changes on disk, so searching always finds what the file says now rather \
+        than what it said when it was first written. files that are copies of each other \
+        share their documents, which keeps the index small when the same notes are kept in \
+        several places, and edited versions of a file can be found from any of them by \
+        searching for documents whose fingerprints are only a few bits apart";
+
+    fn share_band(a: u64, b: u64) -> bool {
+        let a = bands(a);
+        bands(b).iter().any(|band| a.contains(band))
+    }
+
+    #[test]
+    fn short_text_has_no_fingerprint() {
+        assert_eq!(fingerprint("just a few words"), None);
+    }
+
+    #[test]
+    fn edits_stay_close() {
+        let original = fingerprint(TEXT).unwrap();
+        let edited = fingerprint(&TEXT.replace("several places", "a few places")).unwrap();
+        let shouted = fingerprint(&TEXT.to_uppercase()).unwrap();
+
+        assert!(distance(original, edited) <= MAX_DISTANCE);
+        assert!(share_band(original, edited));
+        assert_eq!(original, shouted);
+    }
+
+    #[test]
+    fn different_texts_are_far_apart() {
+        let other = "a completely different text about cooking pasta with garlic, olive oil \
+            and fresh basil from the garden, boiled in salted water for about ten minutes \
+            until it is soft but still firm when you bite it, then tossed in the pan";
+
+        let apart = distance(fingerprint(TEXT).unwrap(), fingerprint(other).unwrap());
+        assert!(apart > MAX_DISTANCE);
+    }
+
+    #[test]
+    fn close_fingerprints_share_a_band() {
+        let fp = fingerprint(TEXT).unwrap();
+
+        for i in 0..64 {
+            for j in i..64 {
+                for k in j..64 {
+                    let near = fp ^ (1 << i) ^ (1 << j) ^ (1 << k);
+                    assert!(share_band(fp, near), "{:x} and {:x}", fp, near);
+                }
+            }
+        }
+    }
+}